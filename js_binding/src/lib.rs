@@ -8,7 +8,6 @@ extern crate js_native;
 
 #[macro_use]
 extern crate log;
-extern crate env_logger;
 
 #[macro_use]
 extern crate lazy_static;
@@ -21,14 +20,21 @@ mod polyfills;
 
 use polyfills::console::console_register;
 use polyfills::timer::timer_register;
+use polyfills::websocket::websocket_register;
 
 pub fn init_js_binding(ctx: &DukContext) -> DukResult<()> {
     // 注册日志模块
     console_register(ctx)?;
     // 注册时间类模块
     timer_register(ctx)?;
+    // 注册WebSocket
+    websocket_register(ctx)?;
+    // Promise实现
+    ctx.init_promise()?;
 
     Ok(())
 }
 
+pub use polyfills::console::{set_console_sink, ConsoleSink, LogSink, MemorySink};
+pub use polyfills::event_loop::EventLoop;
 pub use polyfills::timer::enter_frame;
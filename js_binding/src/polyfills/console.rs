@@ -1,64 +1,348 @@
 //!
 //! 日志实现
-//! https://github.com/svaarala/duktape/blob/master/extras/console/duk_console.c
-//! rust 版本
+//! https://console.spec.whatwg.org/
+//!
+//! 格式化在js中完成,输出交给`ConsoleSink`,默认为转发到`log`库的`LogSink`。
+//! 不会初始化全局logger,由宿主自行设置。
 //!
 
-use chrono::Local;
 use js_native::prelude::*;
-use std::io::Write;
+use js_native::Key;
+use log::Level;
+use std::cell::RefCell;
+use std::rc::Rc;
 
-pub fn console_register(ctx: &DukContext) -> DukResult<()> {
-    // 初始化日志
-    let env = env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "trace");
-    env_logger::Builder::from_env(env)
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{} {} [{}:{}:{}]",
-                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.level(),
-                record.module_path().unwrap_or("<unnamed>"),
-                record.line().unwrap_or(0),
-                &record.args()
-            )
-        })
-        .init();
-
-    let mut builder = class::build();
-    let global: Object = ctx.push_global_object().getp()?;
-    builder
-        .method(
-            "log",
-            (1, |ctx: &DukContext, _this: &mut class::Instance| {
-                match ctx.get_type(0) {
-                    Type::Object => {
-                        let log = ctx.get::<Object>(0)?;
-                        info!("obj : {}", log);
-                    }
-                    Type::String => {
-                        let log = ctx.get::<String>(0)?;
-                        info!("string : {:?}", log);
-                    }
-                    Type::Undefined => {
-                        info!("undefined");
-                    }
-                    Type::Number => {
-                        let log = ctx.get::<i32>(0)?;
-                        info!("number : {:?}", log);
-                    }
-                    _ => {}
+/// `LogSink`默认的target
+const TARGET: &'static str = "console";
+
+/// 参数为全局对象和输出函数`print(level, message)`
+static POLYFILL: &'static str = r#"(function (global, print) {
+    var MAX_DEPTH = 2;
+    var MAX_ITEMS = 100;
+    var WIDTH = 80;
+    var indent = '';
+    var counts = {};
+    var timers = {};
+
+    function repeat(s, n) {
+        return new Array(n + 1).join(s);
+    }
+
+    function quote(s) {
+        return "'" + s.replace(/\\/g, '\\\\').replace(/'/g, "\\'").replace(/\n/g, '\\n') + "'";
+    }
+
+    function formatKey(key) {
+        return /^[A-Za-z_$][\w$]*$/.test(key) ? key : quote(key);
+    }
+
+    function typeName(value) {
+        var proto = Object.getPrototypeOf(value);
+        if (proto === null) return '[Object: null prototype]';
+        var ctor = proto.constructor;
+        return typeof ctor === 'function' && ctor.name ? ctor.name : 'Object';
+    }
+
+    function inspect(value, depth, seen) {
+        switch (typeof value) {
+        case 'string':
+            return quote(value);
+        case 'number':
+            return value === 0 && 1 / value < 0 ? '-0' : String(value);
+        case 'function':
+            return '[Function: ' + (value.name || '(anonymous)') + ']';
+        case 'object':
+            if (value === null) return 'null';
+            break;
+        default:
+            return String(value);
+        }
+
+        if (value instanceof Error) return value.stack || String(value);
+        if (value instanceof Date) return isNaN(value.getTime()) ? 'Invalid Date' : value.toISOString();
+        if (value instanceof RegExp) return String(value);
+        if (seen.indexOf(value) >= 0) return '[Circular]';
+
+        var name = typeName(value);
+        var array = Array.isArray(value);
+        if (value instanceof ArrayBuffer) value = new Uint8Array(value);
+        var list = array || ArrayBuffer.isView(value);
+        if (depth > MAX_DEPTH) return '[' + (array ? 'Array' : name) + ']';
+
+        var parts = [];
+        var keys = Object.keys(value);
+        seen.push(value);
+        if (list) {
+            var length = value.length;
+            for (var i = 0; i < length && i < MAX_ITEMS; i++) parts.push(inspect(value[i], depth + 1, seen));
+            if (length > MAX_ITEMS) parts.push('... ' + (length - MAX_ITEMS) + ' more items');
+            keys = keys.filter(function (key) { return !/^\d+$/.test(key); });
+            if (!array) name += '(' + length + ')';
+        }
+        keys.forEach(function (key) {
+            parts.push(formatKey(key) + ': ' + inspect(value[key], depth + 1, seen));
+        });
+        seen.pop();
+
+        var open = list ? '[' : '{', close = list ? ']' : '}';
+        var prefix = name === (array ? 'Array' : 'Object') ? '' : name + ' ';
+        if (parts.length === 0) return prefix + open + close;
+
+        var line = prefix + open + ' ' + parts.join(', ') + ' ' + close;
+        if (line.length <= WIDTH && line.indexOf('\n') < 0) return line;
+        var pad = repeat('  ', depth + 1);
+        return prefix + open + '\n' + pad + parts.join(',\n' + pad) + '\n' + pad.slice(2) + close;
+    }
+
+    function toInteger(arg) {
+        var n = typeof arg === 'number' ? arg : parseInt(arg, 10);
+        return String(n < 0 ? Math.ceil(n) : Math.floor(n));
+    }
+
+    // 第一个参数为字符串时处理%s %d %i %f %o %O %c,剩余参数以空格连接
+    function format(args) {
+        var out = [];
+        var i = 0;
+        if (typeof args[0] === 'string' && args.length > 1) {
+            i = 1;
+            out.push(args[0].replace(/%([sdifoOc%])/g, function (match, spec) {
+                if (spec === '%') return '%';
+                if (i >= args.length) return match;
+                var arg = args[i++];
+                switch (spec) {
+                case 's': return typeof arg === 'object' && arg !== null ? inspect(arg, 1, []) : String(arg);
+                case 'd':
+                case 'i': return toInteger(arg);
+                case 'f': return String(typeof arg === 'number' ? arg : parseFloat(arg));
+                case 'o':
+                case 'O': return inspect(arg, 0, []);
+                default: return '';
                 }
-                Ok(1)
-            }),
-        )
-        .method("error", |ctx: &DukContext, _this: &mut class::Instance| {
-            let log = ctx.get::<String>(0)?;
-            error!("{:?}", log);
-            Ok(1)
+            }));
+        }
+        for (; i < args.length; i++) out.push(typeof args[i] === 'string' ? args[i] : inspect(args[i], 0, []));
+        return out.join(' ');
+    }
+
+    function write(level, text) {
+        print(level, indent ? text.replace(/^/gm, indent) : text);
+    }
+
+    function emit(level, args) {
+        write(level, format(args));
+    }
+
+    function table(data, columns) {
+        var keys = columns ? Array.prototype.map.call(columns, String) : [];
+        var values = false;
+        var rows = Object.keys(data).map(function (index) {
+            var value = data[index];
+            var row = { index: index, cells: {}, value: '' };
+            if (typeof value === 'object' && value !== null) {
+                Object.keys(value).forEach(function (key) {
+                    if (columns && keys.indexOf(key) < 0) return;
+                    if (keys.indexOf(key) < 0) keys.push(key);
+                    row.cells[key] = inspect(value[key], MAX_DEPTH, []);
+                });
+            } else {
+                values = true;
+                row.value = inspect(value, 0, []);
+            }
+            return row;
         });
 
-    global.set("Console", builder);
+        var header = ['(index)'].concat(keys, values ? ['Values'] : []);
+        var lines = rows.map(function (row) {
+            return [row.index].concat(keys.map(function (key) {
+                return row.cells.hasOwnProperty(key) ? row.cells[key] : '';
+            }), values ? [row.value] : []);
+        });
+        var widths = header.map(function (title, i) {
+            return lines.reduce(function (width, line) {
+                return Math.max(width, line[i].length);
+            }, title.length) + 2;
+        });
+        function rule(left, middle, right) {
+            return left + widths.map(function (width) { return repeat('─', width); }).join(middle) + right;
+        }
+        function render(cells) {
+            return '│' + cells.map(function (cell, i) {
+                return ' ' + cell + repeat(' ', widths[i] - cell.length - 1);
+            }).join('│') + '│';
+        }
+        return [rule('┌', '┬', '┐'), render(header), rule('├', '┼', '┤')]
+            .concat(lines.map(render), [rule('└', '┴', '┘')])
+            .join('\n');
+    }
+
+    function label(value) {
+        return value === undefined ? 'default' : String(value);
+    }
+
+    function rest(args, from) {
+        return Array.prototype.slice.call(args, from);
+    }
+
+    var console = {
+        log: function () { emit('info', arguments); },
+        info: function () { emit('info', arguments); },
+        debug: function () { emit('debug', arguments); },
+        warn: function () { emit('warn', arguments); },
+        error: function () { emit('error', arguments); },
+        trace: function () {
+            // 去掉Error和trace自身所在的行
+            var stack = String(new Error().stack).split('\n').slice(2).join('\n');
+            var message = arguments.length > 0 ? 'Trace: ' + format(arguments) : 'Trace';
+            write('trace', stack ? message + '\n' + stack : message);
+        },
+        assert: function (condition) {
+            if (condition) return;
+            var args = rest(arguments, 1);
+            if (typeof args[0] === 'string') args[0] = 'Assertion failed: ' + args[0];
+            else args.unshift('Assertion failed' + (args.length > 0 ? ':' : ''));
+            emit('error', args);
+        },
+        dir: function (value) {
+            write('info', inspect(value, 0, []));
+        },
+        table: function (data, columns) {
+            if (typeof data !== 'object' || data === null) return emit('info', arguments);
+            write('info', table(data, columns));
+        },
+        group: function () {
+            if (arguments.length > 0) emit('info', arguments);
+            indent += '  ';
+        },
+        groupEnd: function () {
+            indent = indent.slice(2);
+        },
+        count: function (name) {
+            name = label(name);
+            counts[name] = (counts[name] || 0) + 1;
+            write('info', name + ': ' + counts[name]);
+        },
+        countReset: function (name) {
+            counts[label(name)] = 0;
+        },
+        time: function (name) {
+            name = label(name);
+            if (timers.hasOwnProperty(name)) return write('warn', "Timer '" + name + "' already exists");
+            timers[name] = Date.now();
+        },
+        timeLog: function (name) {
+            name = label(name);
+            if (!timers.hasOwnProperty(name)) return write('warn', "Timer '" + name + "' does not exist");
+            var text = name + ': ' + (Date.now() - timers[name]) + 'ms';
+            var args = rest(arguments, 1);
+            write('info', args.length > 0 ? text + ' ' + format(args) : text);
+        },
+        timeEnd: function (name) {
+            name = label(name);
+            if (!timers.hasOwnProperty(name)) return write('warn', "Timer '" + name + "' does not exist");
+            write('info', name + ': ' + (Date.now() - timers[name]) + 'ms');
+            delete timers[name];
+        }
+    };
+    console.groupCollapsed = console.group;
+
+    global.console = console;
+    // 兼容 console = new Console()
+    global.Console = function Console() { return console; };
+})"#;
+
+/// console输出的目标,如游戏内的控制台、文件或测试中的缓冲区
+pub trait ConsoleSink {
+    /// 写入一条格式化后的消息,`console.group`的缩进已包含在`message`中
+    fn write(&self, level: Level, message: &str);
+}
+
+/// 转发到`log`库
+#[derive(Debug, Clone)]
+pub struct LogSink {
+    target: String,
+}
+
+impl LogSink {
+    pub fn new(target: &str) -> LogSink {
+        LogSink {
+            target: target.to_owned(),
+        }
+    }
+}
+
+impl Default for LogSink {
+    fn default() -> LogSink {
+        LogSink::new(TARGET)
+    }
+}
+
+impl ConsoleSink for LogSink {
+    fn write(&self, level: Level, message: &str) {
+        log!(target: &self.target, level, "{}", message);
+    }
+}
+
+/// 保存在内存中,克隆的`MemorySink`共享同一个缓冲区
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    lines: Rc<RefCell<Vec<(Level, String)>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    /// 取出已写入的消息
+    pub fn take(&self) -> Vec<(Level, String)> {
+        self.lines.replace(Vec::new())
+    }
+}
+
+impl ConsoleSink for MemorySink {
+    fn write(&self, level: Level, message: &str) {
+        self.lines.borrow_mut().push((level, message.to_owned()));
+    }
+}
+
+struct Sink;
+
+impl Key for Sink {
+    type Value = Rc<dyn ConsoleSink>;
+}
+
+///
+/// 设置`ctx`的console输出,未设置时使用`LogSink::default()`
+///
+pub fn set_console_sink<T: 'static + ConsoleSink>(ctx: &DukContext, sink: T) -> DukResult<()> {
+    ctx.data_mut()?.insert::<Sink>(Rc::new(sink));
+    Ok(())
+}
+
+///
+/// 注册全局console对象
+///
+/// 同时保留`Console`构造函数,`new Console()`返回全局console
+///
+pub fn console_register(ctx: &DukContext) -> DukResult<()> {
+    ctx.eval(POLYFILL)?;
+    ctx.push_global_object();
+    ctx.push_function((2, |ctx: &DukContext| {
+        let level = ctx
+            .get::<String>(0)?
+            .parse::<Level>()
+            .unwrap_or(Level::Info);
+        let message = ctx.get::<String>(1)?;
+
+        // 写入时不借用context的数据
+        let sink = ctx.data()?.get::<Sink>().cloned();
+        match sink {
+            Some(sink) => sink.write(level, &message),
+            None => LogSink::default().write(level, &message),
+        }
+        Ok(0)
+    }));
+    ctx.call(2)?.pop(1);
 
     Ok(())
 }
@@ -69,6 +353,137 @@ mod test {
     use super::*;
     use crate::init_js_binding;
 
+    /// 安装console,输出保存在全局数组`out`中
+    fn capture() -> DukContext {
+        let ctx = DukContext::new().unwrap();
+        ctx.eval(POLYFILL).unwrap();
+        ctx.push_global_object();
+        ctx.eval("var out = []; (function (level, msg) { out.push(level + '|' + msg); })")
+            .unwrap();
+        ctx.call(2).unwrap().pop(1);
+        ctx
+    }
+
+    fn output(ctx: &DukContext) -> String {
+        let out: String = ctx.eval("out.join('\\n')").unwrap().getp().unwrap();
+        ctx.eval("out = []").unwrap().pop(1);
+        out
+    }
+
+    #[test]
+    fn formatting() {
+        let ctx = capture();
+        ctx.eval(
+            r#"
+            console.log('a', 1, 1.5, -0, true, null, undefined);
+            console.log('%s is %d years, %i and %f', 'Bob', 42.9, '7.8', '1.25', 'extra');
+            console.info('%c styled %o %%', 'color: red', 'str');
+            console.debug('100%');
+            function Point(x) { this.x = x; }
+            console.warn(new Point(1), function foo() {}, [1, [2, [3, [4]]]]);
+            var o = { a: 1, s: 'x', l: [1, 2], d: { e: { f: {} } } };
+            o.self = o;
+            console.error(o);
+            console.dir('s');
+            "#,
+        )
+        .unwrap()
+        .pop(1);
+
+        assert_eq!(
+            output(&ctx),
+            [
+                "info|a 1 1.5 -0 true null undefined",
+                "info|Bob is 42 years, 7 and 1.25 extra",
+                "info| styled 'str' %",
+                "debug|100%",
+                "warn|Point { x: 1 } [Function: foo] [ 1, [ 2, [ 3, [Array] ] ] ]",
+                "error|{ a: 1, s: 'x', l: [ 1, 2 ], d: { e: { f: [Object] } }, self: [Circular] }",
+                "info|'s'",
+            ]
+            .join("\n")
+        );
+
+        ctx.eval(
+            r#"
+            console.log({
+                alpha: 'aaaaaaaaaaaaaaaaaaaa',
+                beta: 'bbbbbbbbbbbbbbbbbbbb',
+                gamma: { 'delta-key': 'cccccccccccccccccccccccccccccc', epsilon: 'dddddddddddddddddddddddddddddd' }
+            });
+            "#,
+        )
+        .unwrap()
+        .pop(1);
+
+        assert_eq!(
+            output(&ctx),
+            "info|{
+  alpha: 'aaaaaaaaaaaaaaaaaaaa',
+  beta: 'bbbbbbbbbbbbbbbbbbbb',
+  gamma: {
+    'delta-key': 'cccccccccccccccccccccccccccccc',
+    epsilon: 'dddddddddddddddddddddddddddddd'
+  }
+}"
+        );
+    }
+
+    #[test]
+    fn methods() {
+        let ctx = capture();
+        ctx.eval(
+            r#"
+            console.assert(1 === 1, 'never');
+            console.assert(false, 'x %d', 1);
+            console.assert(false);
+            console.group('G');
+            console.log('in\ntwo');
+            console.groupEnd();
+            console.log('out');
+            console.count();
+            console.count();
+            console.count('x');
+            console.countReset();
+            console.count();
+            console.table([{ a: 1, b: 'y' }, { a: 2 }]);
+            console.timeEnd('missing');
+            "#,
+        )
+        .unwrap()
+        .pop(1);
+
+        assert_eq!(
+            output(&ctx),
+            [
+                "error|Assertion failed: x 1",
+                "error|Assertion failed",
+                "info|G",
+                "info|  in\n  two",
+                "info|out",
+                "info|default: 1",
+                "info|default: 2",
+                "info|x: 1",
+                "info|default: 1",
+                "info|┌─────────┬───┬─────┐",
+                "│ (index) │ a │ b   │",
+                "├─────────┼───┼─────┤",
+                "│ 0       │ 1 │ 'y' │",
+                "│ 1       │ 2 │     │",
+                "└─────────┴───┴─────┘",
+                "warn|Timer 'missing' does not exist",
+            ]
+            .join("\n")
+        );
+
+        ctx.eval("console.time('t'); console.timeEnd('t'); console.trace('here');")
+            .unwrap()
+            .pop(1);
+        let out = output(&ctx);
+        assert!(out.starts_with("info|t: "), "{}", out);
+        assert!(out.contains("ms\ntrace|Trace: here"), "{}", out);
+    }
+
     #[test]
     fn test() -> DukResult<()> {
         let ctx = DukContext::new().unwrap();
@@ -77,7 +492,7 @@ mod test {
         ctx.eval(
             r#"
             console = new Console();
-            
+
             for(var i=0,l=10;i<l;i++){
                 console.log("大明在js里面调用了rust。很强");
             }
@@ -85,10 +500,35 @@ mod test {
             console.log(Duktape.version);
         "#,
         )?
-        .get(-1)?;
+        .pop(1);
+
+        let same: bool = ctx.eval("new Console() === console")?.getp()?;
+        assert!(same);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sinks() -> DukResult<()> {
+        let ctx = DukContext::new().unwrap();
+        init_js_binding(&ctx)?;
+        let sink = MemorySink::new();
+        set_console_sink(&ctx, sink.clone())?;
 
-        ctx;
+        ctx.eval("console.warn('careful', 1); console.group(); console.debug({ a: 1 });")?
+            .pop(1);
+        assert_eq!(
+            sink.take(),
+            [
+                (Level::Warn, "careful 1".to_owned()),
+                (Level::Debug, "  { a: 1 }".to_owned()),
+            ]
+        );
+        assert!(sink.take().is_empty());
 
+        // 默认转发到log库,不需要宿主初始化logger
+        set_console_sink(&ctx, LogSink::new("game"))?;
+        ctx.eval("console.error('to log')")?.pop(1);
         Ok(())
     }
 }
@@ -0,0 +1,663 @@
+//!
+//! 事件循环
+//!
+//! 每个`DukContext`拥有独立的`EventLoop`,保存在`data()`中,
+//! 销毁堆时随`data()`一起释放,回调保存在全局stash中,同样随堆释放。
+//! 定时器按到期时间保存在最小堆中,没有到期任务时线程休眠,
+//! 直到下一个定时器到期或其他线程通过`ContextHandle`投递任务。
+//!
+//! `run_until_idle` - 运行直到没有定时器、动画帧和未完成的任务
+//! `run_for` - 运行指定时长
+//! `run_once` - 执行一次已到期的任务,不阻塞
+//! `block_on` - 运行直到future完成,如等待定时器完成的promise
+//! `run_animation_frames` - 由宿主每帧调用,执行requestAnimationFrame的回调
+//!
+
+use js_native::prelude::*;
+use js_native::Key;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+/// 全局stash中保存回调的对象,以定时器id为键,值为`[回调, 参数...]`
+pub(crate) const CALLBACKS: &'static str = "eventTimers";
+
+/// 嵌套层级超过该值后,延时至少为`MIN_NESTED_DELAY`
+const MAX_NESTING: u32 = 5;
+const MIN_NESTED_DELAY: Duration = Duration::from_millis(4);
+
+/// 默认帧率
+const FPS: u32 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TimerKind {
+    Timeout,
+    Interval,
+}
+
+#[derive(Debug)]
+struct Timer {
+    kind: TimerKind,
+    /// 注册时指定的延时,未经过嵌套限制
+    interval: Duration,
+    /// 定时器嵌套层级
+    nesting: u32,
+    /// 堆中对应条目的序号,用于跳过过期的条目
+    seq: u64,
+}
+
+/// 堆条目,到期时间相同时按序号先进先出
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Entry {
+    deadline: Instant,
+    seq: u64,
+    id: i32,
+}
+
+/// 单个`DukContext`的事件循环
+#[derive(Debug)]
+pub struct EventLoop {
+    timers: BinaryHeap<Reverse<Entry>>,
+    active: HashMap<i32, Timer>,
+    seq: u64,
+    /// 下一个定时器id,每个`DukContext`独立计数
+    next_id: i32,
+    /// 正在执行的定时器的嵌套层级,不在定时器中时为0
+    nesting: u32,
+    /// 是否允许字符串回调
+    string_callbacks: bool,
+    /// 等待下一帧执行的requestAnimationFrame回调id,按注册顺序
+    frames: Vec<i32>,
+    /// 正在执行的一帧中尚未执行的回调id
+    running: Vec<i32>,
+    /// 内置的帧间隔,为`None`时由宿主调用`run_animation_frames`
+    frame_interval: Option<Duration>,
+    last_frame: Instant,
+    /// 传给动画帧回调的时间戳的起点
+    origin: Instant,
+    /// 其他线程上仍会投递事件的来源数量,如打开的WebSocket
+    sources: usize,
+}
+
+struct EventLoopKey;
+
+/// `block_on`的唤醒标记,其他线程唤醒时投递空任务结束等待
+struct Woken(AtomicBool, ContextHandle);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+        let _ = self.1.execute(|_| {});
+    }
+}
+
+impl Key for EventLoopKey {
+    type Value = EventLoop;
+}
+
+impl EventLoop {
+    fn new() -> EventLoop {
+        EventLoop {
+            timers: BinaryHeap::new(),
+            active: HashMap::new(),
+            seq: 0,
+            next_id: 1,
+            nesting: 0,
+            string_callbacks: true,
+            frames: Vec::new(),
+            running: Vec::new(),
+            frame_interval: Some(Duration::from_secs(1) / FPS),
+            last_frame: Instant::now(),
+            origin: Instant::now(),
+            sources: 0,
+        }
+    }
+
+    /// 访问`ctx`的事件循环,不存在时创建
+    ///
+    /// 回调执行期间不能持有事件循环的引用
+    fn with<R, F: FnOnce(&mut EventLoop) -> R>(ctx: &DukContext, f: F) -> DukResult<R> {
+        let event_loop = ctx
+            .data_mut()?
+            .entry::<EventLoopKey>()
+            .or_insert_with(EventLoop::new);
+        Ok(f(event_loop))
+    }
+
+    fn schedule(&mut self, id: i32, deadline: Instant) -> u64 {
+        let seq = self.seq;
+        self.seq += 1;
+        self.timers.push(Reverse(Entry { deadline, seq, id }));
+        seq
+    }
+
+    /// 生成定时器id,溢出后从1重新开始
+    pub(crate) fn next_id(ctx: &DukContext) -> DukResult<i32> {
+        EventLoop::with(ctx, |l| {
+            let id = l.next_id;
+            l.next_id = if id == i32::max_value() { 1 } else { id + 1 };
+            id
+        })
+    }
+
+    /// 是否允许字符串作为setTimeout和setInterval的回调,默认允许
+    ///
+    /// 禁止时传入非函数的回调会抛出TypeError
+    pub fn set_string_callbacks(ctx: &DukContext, enabled: bool) -> DukResult<()> {
+        EventLoop::with(ctx, |l| l.string_callbacks = enabled)
+    }
+
+    pub(crate) fn string_callbacks(ctx: &DukContext) -> DukResult<bool> {
+        EventLoop::with(ctx, |l| l.string_callbacks)
+    }
+
+    /// 添加定时器,回调需已保存在`CALLBACKS`中
+    ///
+    /// 在定时器回调中添加时嵌套层级加一
+    pub(crate) fn add_timer(
+        ctx: &DukContext,
+        id: i32,
+        kind: TimerKind,
+        delay: Duration,
+    ) -> DukResult<()> {
+        EventLoop::with(ctx, |l| {
+            let nesting = l.nesting;
+            let seq = l.schedule(id, Instant::now() + clamp(delay, nesting));
+            l.active.insert(
+                id,
+                Timer {
+                    kind,
+                    interval: delay,
+                    nesting: nesting + 1,
+                    seq,
+                },
+            );
+        })
+    }
+
+    /// 取消定时器,不区分timeout和interval
+    pub(crate) fn clear_timer(ctx: &DukContext, id: i32) -> DukResult<()> {
+        let cleared = EventLoop::with(ctx, |l| l.active.remove(&id).is_some())?;
+        if cleared {
+            remove_callback(ctx, id);
+        }
+        Ok(())
+    }
+
+    /// 设置内置的帧率,默认60
+    ///
+    /// 为`None`时事件循环不再自动执行动画帧,由宿主在每次渲染时调用
+    /// `run_animation_frames`,此时等待中的动画帧不会阻止事件循环空闲
+    pub fn set_frame_rate(ctx: &DukContext, fps: Option<u32>) -> DukResult<()> {
+        EventLoop::with(ctx, |l| {
+            l.frame_interval = fps.map(|fps| Duration::from_secs(1) / fps.max(1));
+        })
+    }
+
+    /// 添加下一帧执行的回调,回调需已保存在`CALLBACKS`中
+    pub(crate) fn request_frame(ctx: &DukContext, id: i32) -> DukResult<()> {
+        EventLoop::with(ctx, |l| l.frames.push(id))
+    }
+
+    /// 取消等待执行的回调,包括当前帧中尚未执行的
+    pub(crate) fn cancel_frame(ctx: &DukContext, id: i32) -> DukResult<()> {
+        let cancelled = EventLoop::with(ctx, |l| {
+            let before = l.frames.len() + l.running.len();
+            l.frames.retain(|f| *f != id);
+            l.running.retain(|f| *f != id);
+            l.frames.len() + l.running.len() != before
+        })?;
+        if cancelled {
+            remove_callback(ctx, id);
+        }
+        Ok(())
+    }
+
+    /// 执行当前等待的所有动画帧回调,返回执行的数量
+    ///
+    /// 回调收到相同的时间戳,为事件循环创建以来的毫秒数。
+    /// 回调中再次请求的动画帧在下一帧执行
+    pub fn run_animation_frames(ctx: &DukContext) -> DukResult<usize> {
+        let timestamp = EventLoop::with(ctx, |l| {
+            let now = Instant::now();
+            l.last_frame = now;
+            l.running = std::mem::replace(&mut l.frames, Vec::new());
+            now.duration_since(l.origin).as_secs_f64() * 1000.0
+        })?;
+
+        let mut count = 0;
+        loop {
+            let next = EventLoop::with(ctx, |l| {
+                if l.running.is_empty() {
+                    None
+                } else {
+                    Some(l.running.remove(0))
+                }
+            })?;
+            let id = match next {
+                Some(id) => id,
+                None => break,
+            };
+
+            invoke(ctx, id, Some(timestamp));
+            remove_callback(ctx, id);
+            ctx.run_microtasks()?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// 内置帧率下是否到了执行动画帧的时间
+    fn frame_due(&self, now: Instant) -> bool {
+        self.next_frame().map_or(false, |f| f <= now)
+    }
+
+    fn next_frame(&self) -> Option<Instant> {
+        if self.frames.is_empty() {
+            return None;
+        }
+        self.frame_interval
+            .map(|interval| self.last_frame + interval)
+    }
+
+    /// 取出一个在`now`之前到期,且序号小于`limit`的定时器,
+    /// 返回id、类型和嵌套层级
+    fn pop_due(&mut self, now: Instant, limit: u64) -> Option<(i32, TimerKind, u32)> {
+        loop {
+            match self.timers.peek() {
+                Some(Reverse(e)) if e.deadline <= now && e.seq < limit => {}
+                _ => return None,
+            }
+            let Reverse(entry) = self.timers.pop()?;
+
+            let (kind, interval, nesting) = match self.active.get(&entry.id) {
+                Some(t) if t.seq == entry.seq => (t.kind, t.interval, t.nesting),
+                // 已取消或已重新调度
+                _ => continue,
+            };
+            match kind {
+                TimerKind::Timeout => {
+                    self.active.remove(&entry.id);
+                }
+                TimerKind::Interval => {
+                    // 重复执行视为在自身回调中重新注册
+                    let seq = self.schedule(entry.id, now + clamp(interval, nesting));
+                    if let Some(t) = self.active.get_mut(&entry.id) {
+                        t.seq = seq;
+                        t.nesting = nesting + 1;
+                    }
+                }
+            }
+            return Some((entry.id, kind, nesting));
+        }
+    }
+
+    /// 下一个定时器或动画帧的到期时间
+    fn next_wake(&self) -> Option<Instant> {
+        let timer = self.timers.peek().map(|Reverse(e)| e.deadline);
+        let frame = self.next_frame();
+        match (timer, frame) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// 添加一个事件来源,移除前事件循环不会空闲
+    ///
+    /// 来源的事件通过`ContextHandle`投递
+    pub(crate) fn add_source(ctx: &DukContext) -> DukResult<()> {
+        EventLoop::with(ctx, |l| l.sources += 1)
+    }
+
+    pub(crate) fn remove_source(ctx: &DukContext) -> DukResult<()> {
+        EventLoop::with(ctx, |l| l.sources = l.sources.saturating_sub(1))
+    }
+
+    /// 没有定时器、动画帧、事件来源和未完成的future时为空闲
+    pub fn is_idle(ctx: &DukContext) -> bool {
+        let waiting = EventLoop::with(ctx, |l| {
+            !l.active.is_empty()
+                || l.sources > 0
+                || (!l.frames.is_empty() && l.frame_interval.is_some())
+        });
+        !waiting.unwrap_or(false) && ctx.pending_futures() == 0
+    }
+
+    /// 执行已到期的任务,执行回调的时间超过`budget`后返回,
+    /// 剩余的任务留到下次调用。返回是否还有未完成的任务
+    pub fn run_once(ctx: &DukContext, budget: Duration) -> DukResult<bool> {
+        tick(ctx, Some(budget))?;
+        Ok(!EventLoop::is_idle(ctx))
+    }
+
+    /// 运行`duration`时长,提前空闲时返回。返回是否还有未完成的任务
+    pub fn run_for(ctx: &DukContext, duration: Duration) -> DukResult<bool> {
+        let end = Instant::now() + duration;
+        loop {
+            tick(ctx, None)?;
+            if EventLoop::is_idle(ctx) {
+                return Ok(false);
+            }
+
+            let now = Instant::now();
+            if now >= end {
+                return Ok(true);
+            }
+            let wake = EventLoop::with(ctx, |l| l.next_wake())?.map_or(end, |w| w.min(end));
+            ctx.wait_jobs(Some(wake.saturating_duration_since(now)))?;
+        }
+    }
+
+    /// 运行直到空闲
+    pub fn run_until_idle(ctx: &DukContext) -> DukResult<()> {
+        loop {
+            tick(ctx, None)?;
+            if EventLoop::is_idle(ctx) {
+                return Ok(());
+            }
+
+            // 只剩未完成的future或事件来源时,等待其他线程唤醒
+            let wait = EventLoop::with(ctx, |l| l.next_wake())?
+                .map(|w| w.saturating_duration_since(Instant::now()));
+            ctx.wait_jobs(wait)?;
+        }
+    }
+
+    /// 运行事件循环直到`future`完成,返回其输出
+    ///
+    /// 与`DukContext::block_on`不同,期间同样执行定时器和动画帧
+    pub fn block_on<F: Future>(ctx: &DukContext, future: F) -> DukResult<F::Output> {
+        let mut future = Box::pin(future);
+        let woken = Arc::new(Woken(AtomicBool::new(true), ctx.handle()?));
+        let waker = Waker::from(woken.clone());
+        loop {
+            if woken.0.swap(false, Ordering::SeqCst) {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&waker))
+                {
+                    return Ok(output);
+                }
+            }
+
+            tick(ctx, None)?;
+            if !woken.0.load(Ordering::SeqCst) {
+                let wait = EventLoop::with(ctx, |l| l.next_wake())?
+                    .map(|w| w.saturating_duration_since(Instant::now()));
+                ctx.wait_jobs(wait)?;
+            }
+        }
+    }
+}
+
+/// 执行一轮事件循环
+fn tick(ctx: &DukContext, budget: Option<Duration>) -> DukResult<()> {
+    let start = Instant::now();
+
+    // 处理调试器消息,未监听时无操作
+    if let Err(e) = ctx.cooperate_debugger() {
+        error!("debugger {}", e);
+    }
+    // 执行其他线程投递的任务
+    ctx.run_jobs()?;
+    ctx.run_microtasks()?;
+
+    // 本轮重新调度的interval留到下一轮
+    let limit = EventLoop::with(ctx, |l| l.seq)?;
+    loop {
+        if budget.map_or(false, |b| start.elapsed() >= b) {
+            return Ok(());
+        }
+        let (id, kind, nesting) = match EventLoop::with(ctx, |l| l.pop_due(start, limit))? {
+            Some(due) => due,
+            None => break,
+        };
+
+        EventLoop::with(ctx, |l| l.nesting = nesting)?;
+        invoke(ctx, id, None);
+        EventLoop::with(ctx, |l| l.nesting = 0)?;
+        if kind == TimerKind::Timeout {
+            remove_callback(ctx, id);
+        }
+        ctx.run_microtasks()?;
+    }
+
+    if EventLoop::with(ctx, |l| l.frame_due(Instant::now()))? {
+        EventLoop::run_animation_frames(ctx)?;
+    }
+
+    Ok(())
+}
+
+/// 嵌套层级超过`MAX_NESTING`时限制最小延时
+fn clamp(delay: Duration, nesting: u32) -> Duration {
+    if nesting > MAX_NESTING && delay < MIN_NESTED_DELAY {
+        MIN_NESTED_DELAY
+    } else {
+        delay
+    }
+}
+
+/// 调用保存在`CALLBACKS`中的回调,`arg`追加在保存的参数之后,
+/// 错误只记录日志
+fn invoke(ctx: &DukContext, id: i32, arg: Option<f64>) {
+    ctx.push_global_stash().get_prop_string(-1, CALLBACKS);
+    ctx.push_number(id);
+    if let Err(e) = ctx.duk_get_prop(-2) {
+        error!("timer {} {}", id, e);
+        ctx.pop(2);
+        return;
+    }
+    if ctx.is_array(-1) {
+        let len = ctx.get_length(-1) as u32;
+        ctx.get_prop_index(-1, 0);
+        let ret = if ctx.is_function(-1) {
+            for i in 1..len {
+                ctx.get_prop_index(-1 - i as i32, i);
+            }
+            if let Some(arg) = arg {
+                ctx.push_number(arg);
+            }
+            ctx.call(len as i32 - 1 + arg.is_some() as i32)
+        } else {
+            // 字符串回调作为全局脚本求值,忽略参数
+            let code = ctx.get_string(-1).unwrap_or("").to_owned();
+            ctx.pop(1);
+            ctx.eval(code)
+        };
+        // 出错时错误已出栈
+        match ret {
+            Ok(_) => {
+                ctx.pop(1);
+            }
+            Err(e) => error!("timer {} {}", id, e),
+        }
+    }
+    ctx.pop(3);
+}
+
+fn remove_callback(ctx: &DukContext, id: i32) {
+    ctx.push_global_stash()
+        .get_prop_string(-1, CALLBACKS)
+        .del_prop_index(-1, id as u32)
+        .pop(2);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::polyfills::timer::timer_register;
+
+    fn order(ctx: &DukContext) -> String {
+        ctx.eval("order.join()").unwrap().getp().unwrap()
+    }
+
+    #[test]
+    fn timers_run_by_deadline() {
+        let ctx = DukContext::new().unwrap();
+        timer_register(&ctx).unwrap();
+        ctx.eval(
+            r#"
+            var order = [];
+            setTimeout(function () { order.push('b'); }, 20);
+            setTimeout(function () { order.push('a'); }, 10);
+            var cleared = setTimeout(function () { order.push('x'); }, 5);
+            clearTimeout(cleared);
+            var count = 0;
+            var id = setInterval(function () {
+                order.push('i');
+                if (++count === 3) clearInterval(id);
+            }, 8);
+            "#,
+        )
+        .unwrap()
+        .pop(1);
+
+        let start = Instant::now();
+        EventLoop::run_until_idle(&ctx).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(EventLoop::is_idle(&ctx));
+
+        let order = order(&ctx);
+        let timeouts: Vec<&str> = order.split(',').filter(|s| *s != "i").collect();
+        assert_eq!(timeouts, ["a", "b"]);
+        assert_eq!(order.matches('i').count(), 3);
+    }
+
+    #[test]
+    fn run_for_and_run_once() {
+        let ctx = DukContext::new().unwrap();
+        timer_register(&ctx).unwrap();
+        ctx.eval(
+            r#"
+            var order = [];
+            setTimeout(function () { order.push('now'); }, 0);
+            setTimeout(function () { order.push('later'); }, 10000);
+            "#,
+        )
+        .unwrap()
+        .pop(1);
+
+        assert!(EventLoop::run_once(&ctx, Duration::from_millis(100)).unwrap());
+        assert_eq!(order(&ctx), "now");
+
+        let start = Instant::now();
+        assert!(EventLoop::run_for(&ctx, Duration::from_millis(20)).unwrap());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(order(&ctx), "now");
+    }
+
+    #[test]
+    fn independent_contexts() {
+        let a = DukContext::new().unwrap();
+        let b = DukContext::new().unwrap();
+        let script = r#"
+            var order = [];
+            var first = setTimeout(function () { order.push('first'); }, 1);
+            var second = setTimeout(function () { order.push('second'); }, 2);
+            [first, second].join()
+            "#;
+
+        timer_register(&a).unwrap();
+        timer_register(&b).unwrap();
+        let ids_a: String = a.eval(script).unwrap().getp().unwrap();
+        let ids_b: String = b.eval(script).unwrap().getp().unwrap();
+        // 每个context的id独立计数
+        assert_eq!(ids_a, "1,2");
+        assert_eq!(ids_b, "1,2");
+
+        // 清除a的定时器不影响b中相同id的定时器
+        a.eval("clearTimeout(first)").unwrap().pop(1);
+        EventLoop::run_until_idle(&a).unwrap();
+        assert_eq!(order(&a), "second");
+        assert!(!EventLoop::is_idle(&b));
+
+        // 销毁a后b的定时器仍然有效
+        drop(a);
+        EventLoop::run_until_idle(&b).unwrap();
+        assert_eq!(order(&b), "first,second");
+
+        let c = DukContext::new().unwrap();
+        timer_register(&c).unwrap();
+        let id: i32 = c
+            .eval("setTimeout(function () {}, 0)")
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert_eq!(id, 1);
+    }
+
+    #[test]
+    fn animation_frames() {
+        let ctx = DukContext::new().unwrap();
+        timer_register(&ctx).unwrap();
+        EventLoop::set_frame_rate(&ctx, None).unwrap();
+        ctx.eval(
+            r#"
+            var order = [];
+            var stamps = [];
+            requestAnimationFrame(function (t) {
+                stamps.push(t);
+                order.push('a');
+                cancelAnimationFrame(cancelled);
+                requestAnimationFrame(function (t) { stamps.push(t); order.push('next'); });
+            });
+            var cancelled = requestAnimationFrame(function () { order.push('x'); });
+            requestAnimationFrame(function (t) { stamps.push(t); order.push('b'); });
+            "#,
+        )
+        .unwrap()
+        .pop(1);
+
+        // 由宿主驱动时等待中的动画帧不阻止空闲
+        assert!(EventLoop::is_idle(&ctx));
+        assert_eq!(EventLoop::run_animation_frames(&ctx).unwrap(), 2);
+        assert_eq!(order(&ctx), "a,b");
+        assert_eq!(EventLoop::run_animation_frames(&ctx).unwrap(), 1);
+        assert_eq!(order(&ctx), "a,b,next");
+        assert_eq!(EventLoop::run_animation_frames(&ctx).unwrap(), 0);
+
+        let same: bool = ctx
+            .eval("stamps[0] === stamps[1] && stamps[2] >= stamps[1]")
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert!(same);
+
+        // 内置帧率下动画帧由事件循环执行
+        EventLoop::set_frame_rate(&ctx, Some(100)).unwrap();
+        ctx.eval("requestAnimationFrame(function () { order.push('loop'); })")
+            .unwrap()
+            .pop(1);
+        assert!(!EventLoop::is_idle(&ctx));
+        EventLoop::run_until_idle(&ctx).unwrap();
+        assert_eq!(order(&ctx), "a,b,next,loop");
+    }
+
+    #[test]
+    fn block_on_timers() {
+        let ctx = DukContext::new().unwrap();
+        timer_register(&ctx).unwrap();
+        ctx.init_promise().unwrap();
+        ctx.eval(
+            r#"
+            new Promise(function (resolve) {
+                setTimeout(function () { resolve('done'); }, 10);
+            })
+            "#,
+        )
+        .unwrap();
+
+        let future = ctx.to_future(-1).unwrap();
+        let value: String = EventLoop::block_on(&ctx, future)
+            .unwrap()
+            .unwrap()
+            .get()
+            .unwrap();
+        assert_eq!(value, "done");
+        ctx.pop(1);
+        assert!(EventLoop::is_idle(&ctx));
+    }
+}
@@ -1,10 +1,12 @@
 //!
 //!
 //! `timer` - 计时器实现
+//! `event_loop` - 事件循环
 //! `websocket` - 套接字实现
 //! `console` - 日志实现
 //!  
 
 pub mod console;
+pub mod event_loop;
 pub mod timer;
 pub mod websocket;
@@ -4,166 +4,12 @@
 //! clearTimeout
 //! clearInterval
 //! requestAnimationFrame
+//! cancelAnimationFrame
 //!
 
+use super::event_loop::{EventLoop, TimerKind, CALLBACKS};
 use js_native::prelude::*;
-use std::ptr::null_mut;
-use std::time::{Duration, SystemTime};
-
-/// 默认FPS
-const FPS: u128 = 60;
-
-///
-const EVENT_TIMES: &'static str = "eventTimers";
-
-/// 非线程安全的全局可变变量
-///
-/// 用来保存定时器
-///
-/// 切勿在多线程中使用
-static mut V: *mut Vec<EvTime> = null_mut::<Vec<EvTime>>();
-
-/// 非线程安全的全局唯一ID
-///
-/// 用来自增定时器索引
-///
-/// 切勿在多线程中使用
-static mut GID: *mut i32 = null_mut::<i32>();
-
-/// 非线程安全的RAF
-///
-/// 用来保存唯一requestAnimationFrame实现
-///
-/// 切勿在多线程中使用
-static mut RAF: *mut EvTime = null_mut::<EvTime>();
-
-/// 非线程安全的秒表
-///
-/// 用来驱动requestAnimationFrame
-///
-/// 切勿在多线程中使用
-static mut STOPWATCH: *mut Stopwatch = null_mut::<Stopwatch>();
-
-/// 秒表
-pub struct Stopwatch {
-    watch: SystemTime,
-    start: Duration,
-    delta_time: u128,
-    stop: bool,
-}
-
-impl Stopwatch {
-    pub fn new() -> Self {
-        let sys_now = SystemTime::now();
-        Self {
-            watch: sys_now,
-            start: sys_now.elapsed().unwrap(),
-            delta_time: 0_u128,
-            stop: false,
-        }
-    }
-
-    pub fn update(&mut self) -> u128 {
-        match self.watch.elapsed() {
-            Ok(v) => {
-                self.delta_time = (v - self.start).as_millis();
-                self.delta_time as u128
-            }
-            Err(_) => {
-                let sys_now = SystemTime::now();
-                self.watch = sys_now;
-                self.start = sys_now.elapsed().unwrap();
-                0
-            }
-        }
-    }
-
-    pub fn elapsed(&self) -> u128 {
-        return match self.watch.elapsed() {
-            Ok(v) => v.as_millis() as u128,
-            Err(_) => 0,
-        };
-    }
-
-    pub fn get_current(&mut self) -> u128 {
-        return match self.watch.elapsed() {
-            Ok(v) => {
-                self.start = v;
-                self.start.as_millis()
-            }
-            Err(_) => 0,
-        };
-    }
-
-    pub fn get_last(&self) -> u128 {
-        self.start.as_millis() as u128
-    }
-
-    pub fn is_stop(&self) -> bool {
-        self.stop
-    }
-
-    pub fn stop(&mut self, stop: bool) {
-        self.stop = stop
-    }
-}
-
-#[derive(Debug, PartialEq)]
-enum TimeType {
-    LOOP,
-    TIMEOUT,
-    INTERVAL,
-}
-
-#[derive(Debug)]
-pub struct EvTime {
-    id: i32,
-    delay: u128,
-    timetype: TimeType,
-    start: u128,
-    execute_num: u32,
-    time: SystemTime,
-    dt: u128,
-    delete: bool,
-}
-
-/// 初始化
-fn init_v() {
-    let vec = <Vec<EvTime>>::new();
-    unsafe {
-        if V == null_mut() {
-            V = std::mem::transmute(Box::new(vec));
-        }
-    }
-
-    let gid = 1;
-    unsafe {
-        if GID == null_mut() {
-            GID = std::mem::transmute(Box::new(gid));
-        }
-    }
-
-    let stop_watch = Stopwatch::new();
-    unsafe {
-        if STOPWATCH == null_mut() {
-            STOPWATCH = std::mem::transmute(Box::new(stop_watch));
-        }
-    }
-}
-
-/// id 生成器
-fn generator_id() -> i32 {
-    unsafe {
-        let id = *GID;
-        if id >= i32::max_value() {
-            *GID = 0;
-        } else {
-            *GID = id + 1;
-        }
-
-        id
-    }
-}
+use std::time::Duration;
 
 ///
 /// 注册Timer类到js虚拟机中
@@ -175,6 +21,7 @@ fn generator_id() -> i32 {
 /// clearTimeout
 /// clearInterval
 /// requestAnimationFrame
+/// cancelAnimationFrame
 ///
 /// ## Example
 ///
@@ -189,11 +36,9 @@ fn generator_id() -> i32 {
 /// `ctx` - DukContext
 ///
 pub fn timer_register(ctx: &DukContext) -> DukResult<()> {
-    init_v();
-
     ctx.push_global_stash();
     ctx.push_object();
-    ctx.put_prop_string(-2, EVENT_TIMES);
+    ctx.put_prop_string(-2, CALLBACKS);
     ctx.pop(1);
 
     register_set_timeout(ctx);
@@ -201,72 +46,77 @@ pub fn timer_register(ctx: &DukContext) -> DukResult<()> {
     register_set_interval(ctx);
     register_clear_interval(ctx);
     register_request_animation_frame(ctx);
+    register_cancel_animation_frame(ctx);
 
     Ok(())
 }
 
+///
+/// 运行事件循环,直到没有定时器、动画帧和未完成的任务
+///
+/// 回调中不断请求动画帧时不会返回
+///
 pub fn enter_frame(ctx: &DukContext) {
-    loop {
-        unsafe {
-            let cb = || {
-                let v = &mut *V;
-                for t in v.iter_mut() {
-                    if t.time.elapsed().unwrap().as_millis() - t.start >= t.delay {
-                        if t.timetype == TimeType::TIMEOUT && t.execute_num >= 1 {
-                            continue;
-                        }
-
-                        ctx.push_global_stash();
-                        ctx.get_prop_string(-1, EVENT_TIMES);
-                        ctx.push_number(t.id);
-                        ctx.duk_get_prop(-2);
-                        ctx.call(0).ok();
-                        ctx.pop(1);
-                        ctx.pop(2);
-                        t.execute_num = t.execute_num + 1;
-
-                        t.start = 0;
-                        t.time = SystemTime::now();
-                    }
-                }
-
-                v.retain(|t| !t.delete);
-            };
-
-            cb();
+    if let Err(e) = EventLoop::run_until_idle(ctx) {
+        error!("event loop {}", e);
+    }
+}
 
-            // 优先执行requestAnimationFrame
-            if RAF != null_mut() {
-                let t = &mut *RAF;
-                let frame_time = t.delay;
-                let stop_watch = &mut *STOPWATCH;
+/// 保存回调及`first_arg`之后的参数到全局stash,返回定时器id
+///
+/// 回调不是函数时按规范转换为字符串,执行时作为脚本求值
+fn save_callback(ctx: &DukContext, first_arg: i32) -> DukResult<i32> {
+    let top = ctx.top();
+    if top == 0 {
+        return Err(DukErrorKind::TypeError("callback is required".to_owned()).into());
+    }
+    if !ctx.is_function(0) && !EventLoop::string_callbacks(ctx)? {
+        return Err(DukErrorKind::TypeError("callback must be a function".to_owned()).into());
+    }
 
-                if !stop_watch.is_stop() {
-                    if stop_watch.elapsed() - stop_watch.get_last() > frame_time {
-                        stop_watch.get_current();
+    let id = EventLoop::next_id(ctx)?;
+    ctx.push_global_stash();
+    ctx.get_prop_string(-1, CALLBACKS);
+    ctx.push_number(id);
+    ctx.push_array();
+    if ctx.is_function(0) {
+        ctx.dup(0);
+    } else {
+        ctx.push_string("").dup(0).concat(2)?;
+    }
+    ctx.put_prop_index(-2, 0);
+    for (i, idx) in (first_arg..top).enumerate() {
+        ctx.dup(idx).put_prop_index(-2, i as u32 + 1);
+    }
+    ctx.duk_put_prop(-3)?;
+    ctx.pop(2);
+    Ok(id)
+}
 
-                        ctx.push_global_stash();
-                        ctx.get_prop_string(-1, EVENT_TIMES);
-                        ctx.push_number(t.id);
-                        ctx.duk_get_prop(-2);
+/// 按规范转换延时:与WebIDL的`long`一样按ToInt32转换,
+/// 超出范围的值回绕,NaN和负数视为0
+fn get_delay(ctx: &DukContext, idx: i32) -> DukResult<Duration> {
+    if idx >= ctx.top() {
+        return Ok(Duration::from_millis(0));
+    }
+    let delay = ctx.to_int32(idx)?;
+    Ok(Duration::from_millis(delay.max(0) as u64))
+}
 
-                        ctx.push_number(t.dt as f64);
-                        ctx.call(1).ok();
-                        ctx.pop(1);
-                        ctx.pop(2);
+fn add_timer(ctx: &DukContext, kind: TimerKind) -> DukResult<i32> {
+    let delay = get_delay(ctx, 1)?;
+    let id = save_callback(ctx, 2)?;
+    EventLoop::add_timer(ctx, id, kind, delay)?;
+    Ok(id)
+}
 
-                        let delta_time = stop_watch.update();
-                        t.dt = delta_time;
-                    } else {
-                        use std::thread;
-                        thread::yield_now();
-                    }
-                } else {
-                    break;
-                }
-            }
-        }
+/// clearTimeout和clearInterval可以互换使用,非数字id忽略
+fn clear_timer(ctx: &DukContext) -> DukResult<i32> {
+    if ctx.top() > 0 && ctx.is_number(0) {
+        let id = ctx.get_number(0)? as i32;
+        EventLoop::clear_timer(ctx, id)?;
     }
+    Ok(0)
 }
 
 ///
@@ -274,30 +124,10 @@ pub fn enter_frame(ctx: &DukContext) {
 ///
 fn register_set_timeout(ctx: &DukContext) {
     ctx.push_global_object()
-        .push_function((2, |ctx: &DukContext| {
-            ctx.push_global_stash();
-            ctx.get_prop_string(-1, EVENT_TIMES);
-            unsafe {
-                let v = &mut *V;
-                let id = generator_id();
-                let time = EvTime {
-                    id: id,
-                    start: 0,
-                    execute_num: 0,
-                    delay: ctx.get_number(1)? as u128,
-                    timetype: TimeType::TIMEOUT,
-                    time: SystemTime::now(),
-                    dt: 0,
-                    delete: false,
-                };
-                ctx.push_number(id);
-                v.push(time);
-                ctx.dup(0);
-                ctx.duk_put_prop(-3);
-
-                ctx.push_number(id);
-                Ok(1)
-            }
+        .push_function((class::VARARGS, |ctx: &DukContext| {
+            let id = add_timer(ctx, TimerKind::Timeout)?;
+            ctx.push_number(id);
+            Ok(1)
         }))
         .put_prop_string(-2, "setTimeout")
         .pop(1);
@@ -308,27 +138,7 @@ fn register_set_timeout(ctx: &DukContext) {
 ///
 fn register_clear_timeout(ctx: &DukContext) {
     ctx.push_global_object()
-        .push_function((2, |ctx: &DukContext| {
-            match ctx.get_type(0) {
-                Type::Number => {
-                    let id = ctx.get::<i32>(0)?;
-                    info!("clearTimeout {:?}", id);
-                    unsafe {
-                        let v = &mut *V;
-                        for t in v.iter_mut() {
-                            if t.id == id && t.timetype == TimeType::TIMEOUT {
-                                t.delete = true;
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    error!("clearTimeout args must be number");
-                }
-            }
-
-            Ok(1)
-        }))
+        .push_function((1, clear_timer))
         .put_prop_string(-2, "clearTimeout")
         .pop(1);
 }
@@ -338,30 +148,10 @@ fn register_clear_timeout(ctx: &DukContext) {
 ///
 fn register_set_interval(ctx: &DukContext) {
     ctx.push_global_object()
-        .push_function((2, |ctx: &DukContext| {
-            ctx.push_global_stash();
-            ctx.get_prop_string(-1, EVENT_TIMES);
-            unsafe {
-                let v = &mut *V;
-                let id = generator_id();
-                let time = EvTime {
-                    id: id,
-                    start: 0,
-                    execute_num: 0,
-                    delay: ctx.get_number(1)? as u128,
-                    timetype: TimeType::INTERVAL,
-                    time: SystemTime::now(),
-                    dt: 0,
-                    delete: false,
-                };
-                ctx.push_number(id);
-                v.push(time);
-                ctx.dup(0);
-                ctx.duk_put_prop(-3);
-
-                ctx.push_number(id);
-                Ok(1)
-            }
+        .push_function((class::VARARGS, |ctx: &DukContext| {
+            let id = add_timer(ctx, TimerKind::Interval)?;
+            ctx.push_number(id);
+            Ok(1)
         }))
         .put_prop_string(-2, "setInterval")
         .pop(1);
@@ -372,26 +162,7 @@ fn register_set_interval(ctx: &DukContext) {
 ///
 fn register_clear_interval(ctx: &DukContext) {
     ctx.push_global_object()
-        .push_function((2, |ctx: &DukContext| {
-            match ctx.get_type(0) {
-                Type::Number => {
-                    let id = ctx.get::<i32>(0)?;
-                    unsafe {
-                        let v = &mut *V;
-                        for t in v.iter_mut() {
-                            if t.id == id && t.timetype == TimeType::INTERVAL {
-                                t.delete = true;
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    error!("clearInterval args must be number");
-                }
-            }
-
-            Ok(0)
-        }))
+        .push_function((1, clear_timer))
         .put_prop_string(-2, "clearInterval")
         .pop(1);
 }
@@ -399,49 +170,41 @@ fn register_clear_interval(ctx: &DukContext) {
 ///
 /// 注册requestAnimationFrame
 ///
+/// 回调只在下一帧执行一次,参数为当前帧的时间戳
+///
 fn register_request_animation_frame(ctx: &DukContext) {
     ctx.push_global_object()
-        .push_function((2, |ctx: &DukContext| unsafe {
-            if RAF != null_mut() {
-                error!("requestAnimationFrame只能注册一个回调");
-                Ok(0)
-            } else {
-                ctx.push_global_stash();
-                ctx.get_prop_string(-1, EVENT_TIMES);
-
-                let mut delay = 1000 / FPS;
-                if ctx.get_type(1) != Type::Undefined {
-                    let mut dt = ctx.get_number(1)? as u128;
-                    if dt < FPS {
-                        dt = FPS
-                    };
-                    delay = 1000 / dt;
-                }
-
-                let id = generator_id();
-                let time = EvTime {
-                    id: id,
-                    start: 0,
-                    execute_num: 0,
-                    delay: delay,
-                    timetype: TimeType::LOOP,
-                    time: SystemTime::now(),
-                    delete: false,
-                    dt: 0,
-                };
-
-                RAF = std::mem::transmute(Box::new(time));
-                ctx.push_number(id);
-                ctx.dup(0);
-                ctx.duk_put_prop(-3);
-
-                Ok(0)
+        .push_function((1, |ctx: &DukContext| {
+            if !ctx.is_function(0) {
+                return Err(
+                    DukErrorKind::TypeError("callback must be a function".to_owned()).into(),
+                );
             }
+            let id = save_callback(ctx, ctx.top())?;
+            EventLoop::request_frame(ctx, id)?;
+            ctx.push_number(id);
+            Ok(1)
         }))
         .put_prop_string(-2, "requestAnimationFrame")
         .pop(1);
 }
 
+///
+/// 注册cancelAnimationFrame
+///
+fn register_cancel_animation_frame(ctx: &DukContext) {
+    ctx.push_global_object()
+        .push_function((1, |ctx: &DukContext| {
+            if ctx.is_number(0) {
+                let id = ctx.get_number(0)? as i32;
+                EventLoop::cancel_frame(ctx, id)?;
+            }
+            Ok(0)
+        }))
+        .put_prop_string(-2, "cancelAnimationFrame")
+        .pop(1);
+}
+
 #[cfg(test)]
 mod test {
     use super::DukContext;
@@ -469,16 +232,86 @@ mod test {
             // },21);
 
             // requestAnimationFrame
-            // `dt` - dt is delta time
-            requestAnimationFrame(function(dt){
-                console.log("requestAnimationFrame:"+dt);
+            // `time` - 当前帧的时间戳
+            var frames = 0;
+            requestAnimationFrame(function frame(time){
+                console.log("requestAnimationFrame:"+time);
+                frames++;
+                requestAnimationFrame(frame);
             });
         "#,
         )?
-        .get(-1)?;
+        .pop(1);
+
+        // 每帧重新请求动画帧,事件循环不会空闲
+        assert!(EventLoop::run_for(&ctx, Duration::from_millis(100))?);
+        let frames: i32 = ctx.eval("frames")?.getp()?;
+        assert!(frames > 1);
+        Ok(())
+    }
+
+    #[test]
+    fn spec_semantics() -> DukResult<()> {
+        let ctx = DukContext::new().unwrap();
+        timer_register(&ctx)?;
+
+        ctx.eval(
+            r#"
+            var order = [];
+            setTimeout(function (a, b) { order.push(a + b); }, 0, 'x', 'y');
+            setTimeout("order.push('str')", 0);
+            setTimeout(function () { order.push('fifo'); });
+            var interval = setInterval(function () { order.push('never'); }, 1);
+            clearTimeout(interval);
+            var timeout = setTimeout(function () { order.push('never'); }, 1);
+            clearInterval(timeout);
+            clearTimeout(undefined);
+            "#,
+        )?
+        .pop(1);
+        EventLoop::run_until_idle(&ctx)?;
+        let order: String = ctx.eval("order.join()")?.getp()?;
+        assert_eq!(order, "xy,str,fifo");
 
-        enter_frame(&ctx);
-        ctx;
+        // 延时按ToInt32转换,2^32 + 1回绕为1,2^31回绕为负数
+        ctx.eval(
+            r#"
+            var delays = [];
+            var slow = setTimeout(function () { delays.push('slow'); }, { valueOf: function () { return 10000; } });
+            setTimeout(function () { delays.push('wrapped'); }, 4294967297);
+            setTimeout(function () { delays.push('negative'); }, 2147483648);
+            setTimeout(function () { delays.push('true'); }, true);
+            setTimeout(function () { delays.push('string'); }, ' 1 ');
+            "#,
+        )?
+        .pop(1);
+        assert!(EventLoop::run_for(&ctx, Duration::from_millis(200))?);
+        let delays: String = ctx.eval("clearTimeout(slow); delays.join()")?.getp()?;
+        assert_eq!(delays, "negative,wrapped,true,string");
+        let thrown: bool = ctx
+            .eval("try { setTimeout(function () {}, { valueOf: function () { throw 1; } }); false } catch (e) { e === 1 }")?
+            .getp()?;
+        assert!(thrown);
+
+        // 嵌套超过5层后延时至少4ms
+        ctx.eval(
+            r#"
+            var depth = 0;
+            (function nest() {
+                if (++depth < 12) setTimeout(nest, 0);
+            })();
+            "#,
+        )?
+        .pop(1);
+        let start = std::time::Instant::now();
+        EventLoop::run_until_idle(&ctx)?;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        EventLoop::set_string_callbacks(&ctx, false)?;
+        let thrown: bool = ctx
+            .eval("try { setTimeout('1', 0); false } catch (e) { e instanceof TypeError }")?
+            .getp()?;
+        assert!(thrown);
         Ok(())
     }
 }
@@ -1,3 +1,605 @@
 //!
 //! websocket
-//! 
\ No newline at end of file
+//!
+//! 浏览器`WebSocket`的实现
+//!
+//! 网络读写在后台线程上由`ws`完成,事件通过`ContextHandle`投递回脚本线程,
+//! 由事件循环分发。连接关闭前事件循环不会空闲。
+//!
+//! 没有Blob,`binaryType`设置为`blob`时二进制消息仍然是ArrayBuffer
+//!
+
+use super::event_loop::EventLoop;
+use js_native::prelude::*;
+use js_native::Key;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use ws::{CloseCode, Handshake, Message, Request, Sender};
+
+/// 全局stash中分发事件的函数
+const DISPATCH: &'static str = "webSocketDispatch";
+
+/// 参数为全局对象和原生函数,返回分发事件的函数
+static POLYFILL: &'static str = r#"(function (global, native) {
+    var CONNECTING = 0, OPEN = 1, CLOSING = 2, CLOSED = 3;
+    var sockets = {};
+
+    function domError(name, message) {
+        var e = new Error(message);
+        e.name = name;
+        return e;
+    }
+
+    function utf8Length(s) {
+        return unescape(encodeURIComponent(s)).length;
+    }
+
+    function isBinary(data) {
+        return data instanceof ArrayBuffer || ArrayBuffer.isView(data);
+    }
+
+    function WebSocket(url, protocols) {
+        if (!(this instanceof WebSocket)) throw new TypeError('WebSocket must be called with new');
+        url = String(url);
+        if (!/^wss?:\/\/[^\/]/i.test(url)) throw domError('SyntaxError', "Invalid WebSocket URL '" + url + "'");
+
+        if (protocols === undefined) protocols = [];
+        else if (typeof protocols === 'string') protocols = [protocols];
+        else protocols = Array.prototype.map.call(protocols, String);
+        for (var i = 0; i < protocols.length; i++) {
+            if (protocols.indexOf(protocols[i]) !== i) throw domError('SyntaxError', "Duplicate protocol '" + protocols[i] + "'");
+        }
+
+        var state = { id: 0, readyState: CONNECTING, protocol: '', binaryType: 'arraybuffer', buffered: 0, listeners: {} };
+        Object.defineProperty(this, '_ws', { value: state });
+        Object.defineProperty(this, 'url', { value: url, enumerable: true });
+        this.onopen = null;
+        this.onmessage = null;
+        this.onerror = null;
+        this.onclose = null;
+
+        state.id = native.connect(url, protocols);
+        sockets[state.id] = this;
+    }
+
+    var constants = { CONNECTING: CONNECTING, OPEN: OPEN, CLOSING: CLOSING, CLOSED: CLOSED };
+    for (var name in constants) {
+        Object.defineProperty(WebSocket, name, { value: constants[name], enumerable: true });
+        Object.defineProperty(WebSocket.prototype, name, { value: constants[name], enumerable: true });
+    }
+
+    function accessor(name, get, set) {
+        Object.defineProperty(WebSocket.prototype, name, { get: get, set: set, configurable: true });
+    }
+    accessor('readyState', function () { return this._ws.readyState; });
+    accessor('protocol', function () { return this._ws.protocol; });
+    accessor('extensions', function () { return ''; });
+    accessor('bufferedAmount', function () { return this._ws.buffered; });
+    accessor('binaryType', function () { return this._ws.binaryType; }, function (type) {
+        if (type === 'blob' || type === 'arraybuffer') this._ws.binaryType = type;
+    });
+
+    WebSocket.prototype.send = function (data) {
+        var ws = this._ws;
+        if (ws.readyState === CONNECTING) throw domError('InvalidStateError', 'WebSocket is still in CONNECTING state');
+        if (typeof data !== 'string' && !isBinary(data)) data = String(data);
+        if (ws.readyState !== OPEN) {
+            // 关闭后发送的数据只计入bufferedAmount
+            ws.buffered += typeof data === 'string' ? utf8Length(data) : data.byteLength;
+            return;
+        }
+        native.send(ws.id, data);
+    };
+
+    WebSocket.prototype.close = function (code, reason) {
+        if (code !== undefined) {
+            code = Number(code);
+            if (code !== 1000 && !(code >= 3000 && code <= 4999)) {
+                throw domError('InvalidAccessError', 'The close code must be 1000 or between 3000 and 4999');
+            }
+        }
+        reason = reason === undefined ? '' : String(reason);
+        if (utf8Length(reason) > 123) throw domError('SyntaxError', 'The close reason must not be longer than 123 bytes');
+
+        var ws = this._ws;
+        if (ws.readyState === CLOSING || ws.readyState === CLOSED) return;
+        ws.readyState = CLOSING;
+        native.close(ws.id, code === undefined ? 1000 : code, reason);
+    };
+
+    WebSocket.prototype.addEventListener = function (type, listener) {
+        if (typeof listener !== 'function' && !(listener && typeof listener.handleEvent === 'function')) return;
+        var list = this._ws.listeners[type] || (this._ws.listeners[type] = []);
+        if (list.indexOf(listener) < 0) list.push(listener);
+    };
+
+    WebSocket.prototype.removeEventListener = function (type, listener) {
+        var list = this._ws.listeners[type];
+        var i = list ? list.indexOf(listener) : -1;
+        if (i >= 0) list.splice(i, 1);
+    };
+
+    // on<type>先于addEventListener注册的监听执行,所有监听执行完后抛出第一个异常
+    WebSocket.prototype.dispatchEvent = function (event) {
+        event.target = event.currentTarget = this;
+        var handler = this['on' + event.type];
+        var list = (typeof handler === 'function' ? [handler] : []).concat(this._ws.listeners[event.type] || []);
+        var failed = false, error;
+        for (var i = 0; i < list.length; i++) {
+            try {
+                if (typeof list[i] === 'function') list[i].call(this, event);
+                else list[i].handleEvent(event);
+            } catch (e) {
+                if (!failed) error = e;
+                failed = true;
+            }
+        }
+        if (failed) throw error;
+        return true;
+    };
+
+    global.WebSocket = WebSocket;
+
+    return function (id, type, a, b, c) {
+        var socket = sockets[id];
+        if (!socket) return;
+        var ws = socket._ws;
+        var event = { type: type };
+        switch (type) {
+        case 'open':
+            if (ws.readyState !== CONNECTING) return;
+            ws.readyState = OPEN;
+            ws.protocol = a;
+            break;
+        case 'message':
+            if (ws.readyState !== OPEN) return;
+            event.data = typeof a === 'string' ? a : new Uint8Array(a).buffer;
+            event.origin = socket.url;
+            break;
+        case 'error':
+            event.message = a;
+            break;
+        case 'close':
+            ws.readyState = CLOSED;
+            delete sockets[id];
+            event.code = a;
+            event.reason = b;
+            event.wasClean = c;
+            break;
+        }
+        socket.dispatchEvent(event);
+    };
+})"#;
+
+/// 投递到脚本线程的事件
+enum Event {
+    Open(String),
+    Message(Message),
+    Error(String),
+    Close(u16, String, bool),
+}
+
+/// 与后台线程共享的连接状态
+#[derive(Default)]
+struct Shared {
+    sender: Option<Sender>,
+    open: bool,
+    /// 握手完成前请求的关闭
+    close: Option<(CloseCode, String)>,
+}
+
+/// 脚本线程上的连接
+struct Connection {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // 销毁context时停止后台线程,连接已关闭时无影响
+        if let Ok(shared) = self.shared.lock() {
+            if let Some(sender) = &shared.sender {
+                let _ = sender.shutdown();
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sockets {
+    next_id: i32,
+    connections: HashMap<i32, Connection>,
+}
+
+struct SocketsKey;
+
+impl Key for SocketsKey {
+    type Value = Sockets;
+}
+
+/// 后台线程上的`ws`回调
+struct Client {
+    id: i32,
+    protocols: Vec<String>,
+    handle: ContextHandle,
+    out: Sender,
+    shared: Arc<Mutex<Shared>>,
+    /// 收到的关闭帧
+    closed: Arc<Mutex<Option<(u16, String)>>>,
+}
+
+impl Client {
+    fn post(&self, event: Event) {
+        if !post(&self.handle, self.id, event) {
+            let _ = self.out.shutdown();
+        }
+    }
+}
+
+impl ws::Handler for Client {
+    fn build_request(&mut self, url: &url::Url) -> ws::Result<Request> {
+        let mut request = Request::from_url(url)?;
+        for protocol in self.protocols.iter() {
+            request.add_protocol(protocol);
+        }
+        Ok(request)
+    }
+
+    fn on_open(&mut self, shake: Handshake) -> ws::Result<()> {
+        let close = match self.shared.lock() {
+            Ok(mut shared) => {
+                shared.open = true;
+                shared.close.take()
+            }
+            Err(_) => None,
+        };
+        if let Some((code, reason)) = close {
+            return self.out.close_with_reason(code, reason);
+        }
+
+        let protocol = shake.response.protocol()?.unwrap_or("").to_owned();
+        self.post(Event::Open(protocol));
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        self.post(Event::Message(msg));
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        if let Ok(mut closed) = self.closed.lock() {
+            *closed = Some((code.into(), reason.to_owned()));
+        }
+    }
+
+    fn on_error(&mut self, err: ws::Error) {
+        self.post(Event::Error(err.to_string()));
+    }
+}
+
+///
+/// 注册WebSocket类到js虚拟机中
+///
+/// ## Example
+///
+/// var socket = new WebSocket("ws://127.0.0.1:3012");
+///
+/// socket.onmessage = function(e){ console.log(e.data); };
+///
+/// `ctx` - DukContext
+///
+pub fn websocket_register(ctx: &DukContext) -> DukResult<()> {
+    ctx.push_global_stash();
+    let ret = ctx.eval(POLYFILL).and_then(|ctx| {
+        ctx.push_global_object();
+        push_native(ctx);
+        ctx.call(2)
+    });
+    if let Err(e) = ret {
+        ctx.pop(1);
+        return Err(e);
+    }
+    ctx.put_prop_string(-2, DISPATCH);
+    ctx.pop(1);
+    Ok(())
+}
+
+/// 压入polyfill使用的原生函数
+fn push_native(ctx: &DukContext) {
+    ctx.push_object();
+
+    ctx.push_function((2, |ctx: &DukContext| {
+        let url = ctx.get::<String>(0)?;
+        let mut protocols = Vec::new();
+        for i in 0..ctx.get_length(1) {
+            ctx.get_prop_index(1, i as u32);
+            protocols.push(ctx.get::<String>(-1)?);
+            ctx.pop(1);
+        }
+
+        let id = connect(ctx, url, protocols)?;
+        ctx.push_number(id);
+        Ok(1)
+    }))
+    .put_prop_string(-2, "connect");
+
+    ctx.push_function((2, |ctx: &DukContext| {
+        let id = ctx.get_number(0)? as i32;
+        let msg = if ctx.is_string(1) {
+            Message::Text(ctx.get::<String>(1)?)
+        } else {
+            Message::Binary(ctx.get_bytes(1)?.to_vec())
+        };
+        with_sender(ctx, id, |sender| sender.send(msg))?;
+        Ok(0)
+    }))
+    .put_prop_string(-2, "send");
+
+    ctx.push_function((3, |ctx: &DukContext| {
+        let id = ctx.get_number(0)? as i32;
+        let code = CloseCode::from(ctx.get_number(1)? as u16);
+        let reason = ctx.get::<String>(2)?;
+        close(ctx, id, code, reason)?;
+        Ok(0)
+    }))
+    .put_prop_string(-2, "close");
+}
+
+/// 在后台线程上连接`url`,返回连接id
+fn connect(ctx: &DukContext, url: String, protocols: Vec<String>) -> DukResult<i32> {
+    let handle = ctx.handle()?;
+    let shared = Arc::new(Mutex::new(Shared::default()));
+    let id = {
+        let sockets = ctx
+            .data_mut()?
+            .entry::<SocketsKey>()
+            .or_insert_with(Sockets::default);
+        sockets.next_id += 1;
+        sockets.connections.insert(
+            sockets.next_id,
+            Connection {
+                shared: shared.clone(),
+            },
+        );
+        sockets.next_id
+    };
+    EventLoop::add_source(ctx)?;
+
+    thread::spawn(move || {
+        let closed = Arc::new(Mutex::new(None));
+        let ret = {
+            let handle = handle.clone();
+            let closed = closed.clone();
+            ws::connect(url, move |out: Sender| {
+                if let Ok(mut shared) = shared.lock() {
+                    shared.sender = Some(out.clone());
+                }
+                Client {
+                    id,
+                    protocols: protocols.clone(),
+                    handle: handle.clone(),
+                    out,
+                    shared: shared.clone(),
+                    closed: closed.clone(),
+                }
+            })
+        };
+        if let Err(e) = ret {
+            post(&handle, id, Event::Error(e.to_string()));
+        }
+
+        // 没有收到关闭帧时连接异常断开
+        let event = match closed.lock().ok().and_then(|mut c| c.take()) {
+            Some((code, reason)) => Event::Close(code, reason, true),
+            None => Event::Close(CloseCode::Abnormal.into(), String::new(), false),
+        };
+        post(&handle, id, event);
+    });
+
+    Ok(id)
+}
+
+fn with_sender<F: FnOnce(&Sender) -> ws::Result<()>>(
+    ctx: &DukContext,
+    id: i32,
+    f: F,
+) -> DukResult<()> {
+    let shared = match ctx
+        .data()?
+        .get::<SocketsKey>()
+        .and_then(|s| s.connections.get(&id))
+    {
+        Some(connection) => connection.shared.clone(),
+        None => return Ok(()),
+    };
+    let shared = shared
+        .lock()
+        .map_err(|_| DukErrorKind::Error("websocket is poisoned".to_owned()))?;
+    match &shared.sender {
+        Some(sender) => f(sender).map_err(|e| DukErrorKind::Error(e.to_string()).into()),
+        None => Ok(()),
+    }
+}
+
+/// 关闭连接,握手完成前请求的关闭在握手完成后执行
+fn close(ctx: &DukContext, id: i32, code: CloseCode, reason: String) -> DukResult<()> {
+    let shared = match ctx
+        .data()?
+        .get::<SocketsKey>()
+        .and_then(|s| s.connections.get(&id))
+    {
+        Some(connection) => connection.shared.clone(),
+        None => return Ok(()),
+    };
+    let mut shared = shared
+        .lock()
+        .map_err(|_| DukErrorKind::Error("websocket is poisoned".to_owned()))?;
+    match (shared.open, &shared.sender) {
+        (true, Some(sender)) => sender
+            .close_with_reason(code, reason)
+            .map_err(|e| DukErrorKind::Error(e.to_string()).into()),
+        _ => {
+            shared.close = Some((code, reason));
+            Ok(())
+        }
+    }
+}
+
+/// 投递事件到脚本线程,context已销毁时返回false
+fn post(handle: &ContextHandle, id: i32, event: Event) -> bool {
+    handle
+        .execute(move |ctx| dispatch(ctx, id, event))
+        .is_ok()
+}
+
+/// 在脚本线程上分发事件,错误只记录日志
+fn dispatch(ctx: &DukContext, id: i32, event: Event) {
+    if let Event::Close(..) = event {
+        let removed = ctx
+            .data_mut()
+            .ok()
+            .and_then(|data| data.get_mut::<SocketsKey>())
+            .and_then(|s| s.connections.remove(&id));
+        if removed.is_some() {
+            if let Err(e) = EventLoop::remove_source(ctx) {
+                error!("websocket {} {}", id, e);
+            }
+        }
+    }
+
+    ctx.push_global_stash()
+        .get_prop_string(-1, DISPATCH)
+        .remove(-2);
+    if !ctx.is_function(-1) {
+        ctx.pop(1);
+        return;
+    }
+
+    ctx.push_number(id);
+    let argc = match event {
+        Event::Open(protocol) => {
+            ctx.push_string("open").push_string(protocol);
+            2
+        }
+        Event::Message(Message::Text(text)) => {
+            ctx.push_string("message").push_string(text);
+            2
+        }
+        Event::Message(Message::Binary(data)) => {
+            ctx.push_string("message").push_bytes(data);
+            2
+        }
+        Event::Error(message) => {
+            ctx.push_string("error").push_string(message);
+            2
+        }
+        Event::Close(code, reason, clean) => {
+            ctx.push_string("close")
+                .push_number(code)
+                .push_string(reason)
+                .push_boolean(clean);
+            4
+        }
+    };
+
+    // 出错时错误已出栈
+    match ctx.call(argc + 1) {
+        Ok(_) => {
+            ctx.pop(1);
+        }
+        Err(e) => error!("websocket {} {}", id, e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    fn events(ctx: &DukContext) -> String {
+        ctx.eval("events.join()").unwrap().getp().unwrap()
+    }
+
+    #[test]
+    fn echo() {
+        let server = ws::WebSocket::new(|out: Sender| move |msg: Message| out.send(msg))
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let broadcaster = server.broadcaster();
+        let server = thread::spawn(move || {
+            server.run().unwrap();
+        });
+
+        let ctx = DukContext::new().unwrap();
+        websocket_register(&ctx).unwrap();
+        ctx.eval(format!("var url = '{}';", url)).unwrap().pop(1);
+        ctx.eval(
+            r#"
+            var events = [];
+            var socket = new WebSocket(url);
+            try { socket.send('early'); } catch (e) { events.push(e.name); }
+            socket.onopen = function () {
+                events.push('open:' + socket.readyState);
+                socket.send('hello');
+                socket.send(new Uint8Array([1, 2, 3]).buffer);
+            };
+            socket.addEventListener('message', function (e) {
+                events.push(typeof e.data === 'string'
+                    ? e.data
+                    : Array.prototype.join.call(new Uint8Array(e.data), '-'));
+                if (e.data instanceof ArrayBuffer) socket.close(1000, 'done');
+            });
+            socket.onclose = function (e) {
+                events.push('close:' + e.code + ':' + e.reason + ':' + e.wasClean);
+                events.push(socket.readyState);
+            };
+            "#,
+        )
+        .unwrap()
+        .pop(1);
+
+        assert!(!EventLoop::is_idle(&ctx));
+        assert!(!EventLoop::run_for(&ctx, Duration::from_secs(10)).unwrap());
+        assert_eq!(
+            events(&ctx),
+            "InvalidStateError,open:1,hello,1-2-3,close:1000:done:true,3"
+        );
+
+        broadcaster.shutdown().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connection_refused() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let ctx = DukContext::new().unwrap();
+        websocket_register(&ctx).unwrap();
+        ctx.eval(format!("var url = 'ws://{}';", addr)).unwrap().pop(1);
+        ctx.eval(
+            r#"
+            var events = [];
+            var socket = new WebSocket(url);
+            socket.onerror = function () { events.push('error'); };
+            socket.onclose = function (e) { events.push('close:' + e.code + ':' + e.wasClean); };
+            try { new WebSocket('http://localhost'); } catch (e) { events.push(e.name); }
+            try { socket.close(1001); } catch (e) { events.push(e.name); }
+            "#,
+        )
+        .unwrap()
+        .pop(1);
+
+        assert!(!EventLoop::run_for(&ctx, Duration::from_secs(10)).unwrap());
+        assert_eq!(
+            events(&ctx),
+            "SyntaxError,InvalidAccessError,error,close:1006:false"
+        );
+    }
+}
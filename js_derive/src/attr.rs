@@ -0,0 +1,73 @@
+//!
+//! `#[js(...)]` attributes
+//!
+
+use syn::{Attribute, Error, Lit, Meta, NestedMeta, Result};
+
+/// Options parsed from the `#[js(...)]` attributes of an item
+#[derive(Default)]
+pub struct Options {
+    pub name: Option<String>,
+    pub rename: Option<String>,
+    pub get: bool,
+    pub set: bool,
+    pub skip: bool,
+    pub default: bool,
+    pub flatten: bool,
+    pub constructor: bool,
+}
+
+impl Options {
+    pub fn parse(attrs: &[Attribute]) -> Result<Options> {
+        let mut opts = Options::default();
+        for attr in attrs.iter().filter(|a| a.path.is_ident("js")) {
+            let list = match attr.parse_meta()? {
+                Meta::List(list) => list,
+                meta => return Err(Error::new_spanned(meta, "expected #[js(...)]")),
+            };
+            for nested in list.nested {
+                match nested {
+                    NestedMeta::Meta(Meta::Path(ref path)) => {
+                        let flag = if path.is_ident("get") {
+                            &mut opts.get
+                        } else if path.is_ident("set") {
+                            &mut opts.set
+                        } else if path.is_ident("skip") {
+                            &mut opts.skip
+                        } else if path.is_ident("default") {
+                            &mut opts.default
+                        } else if path.is_ident("flatten") {
+                            &mut opts.flatten
+                        } else if path.is_ident("constructor") {
+                            &mut opts.constructor
+                        } else {
+                            return Err(Error::new_spanned(path, "unknown js attribute"));
+                        };
+                        *flag = true;
+                    }
+                    NestedMeta::Meta(Meta::NameValue(ref nv)) => {
+                        let value = match nv.lit {
+                            Lit::Str(ref s) => s.value(),
+                            ref lit => return Err(Error::new_spanned(lit, "expected a string")),
+                        };
+                        if nv.path.is_ident("name") {
+                            opts.name = Some(value);
+                        } else if nv.path.is_ident("rename") {
+                            opts.rename = Some(value);
+                        } else {
+                            return Err(Error::new_spanned(&nv.path, "unknown js attribute"));
+                        }
+                    }
+                    nested => return Err(Error::new_spanned(nested, "unknown js attribute")),
+                }
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// Remove the `#[js(...)]` attributes, which are not known to the compiler
+/// outside of derives
+pub fn strip(attrs: &mut Vec<Attribute>) {
+    attrs.retain(|a| !a.path.is_ident("js"));
+}
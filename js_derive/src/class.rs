@@ -0,0 +1,163 @@
+//!
+//! `#[derive(JsClass)]` and `#[js_methods]`
+//!
+
+use super::attr::{strip, Options};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{
+    Data, DeriveInput, Error, Fields, FnArg, ImplItem, ImplItemMethod, ItemImpl, Result, Type,
+};
+
+pub fn derive(input: &DeriveInput) -> Result<TokenStream> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "JsClass cannot be derived for generic types",
+        ));
+    }
+
+    let ident = &input.ident;
+    let name = Options::parse(&input.attrs)?
+        .name
+        .unwrap_or_else(|| ident.to_string());
+
+    let mut properties = Vec::new();
+    if let Data::Struct(ref data) = input.data {
+        if let Fields::Named(ref fields) = data.fields {
+            for field in &fields.named {
+                let opts = Options::parse(&field.attrs)?;
+                let field_ident = field.ident.as_ref().unwrap();
+                let ty = &field.ty;
+                let js_name = opts.rename.unwrap_or_else(|| field_ident.to_string());
+
+                if opts.get {
+                    properties.push(quote! {
+                        builder.getter(#js_name, |ctx: &::js_native::DukContext, instance: &mut ::js_native::class::Instance| -> ::js_native::error::Result<i32> {
+                            let this = ::js_native::class::this::<Self>(instance)?;
+                            ctx.push(::std::clone::Clone::clone(&this.#field_ident))?;
+                            Ok(1)
+                        });
+                    });
+                }
+                if opts.set {
+                    properties.push(quote! {
+                        builder.setter(#js_name, (1, |ctx: &::js_native::DukContext, instance: &mut ::js_native::class::Instance| -> ::js_native::error::Result<i32> {
+                            let value = <#ty as ::js_native::Argument>::from_arguments(ctx, 0)?;
+                            ::js_native::class::this::<Self>(instance)?.#field_ident = value;
+                            Ok(0)
+                        }));
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(quote! {
+        impl ::js_native::class::JsClass for #ident {
+            const NAME: &'static str = #name;
+
+            #[allow(unused_variables)]
+            fn properties(builder: &mut ::js_native::class::Builder) {
+                #(#properties)*
+            }
+        }
+    })
+}
+
+pub fn methods(mut item: ItemImpl) -> Result<TokenStream> {
+    if let Some((_, ref path, _)) = item.trait_ {
+        return Err(Error::new_spanned(
+            path,
+            "js_methods only applies to inherent impls",
+        ));
+    }
+
+    let self_ty = item.self_ty.clone();
+    let mut registrations = Vec::new();
+    for impl_item in item.items.iter_mut() {
+        if let ImplItem::Method(ref mut method) = *impl_item {
+            let opts = Options::parse(&method.attrs)?;
+            strip(&mut method.attrs);
+            if opts.skip {
+                continue;
+            }
+            if let Some(registration) = register(&self_ty, method, opts)? {
+                registrations.push(registration);
+            }
+        }
+    }
+
+    Ok(quote! {
+        #item
+
+        impl ::js_native::class::JsMethods for #self_ty {
+            #[allow(unused_variables)]
+            fn methods(builder: &mut ::js_native::class::Builder) {
+                #(#registrations)*
+            }
+        }
+    })
+}
+
+/// The registration of a method with the class builder, if it is exposed
+fn register(self_ty: &Type, method: &ImplItemMethod, opts: Options) -> Result<Option<TokenStream>> {
+    let ident = &method.sig.ident;
+    let js_name = opts.rename.unwrap_or_else(|| ident.to_string());
+
+    let mut receiver = None;
+    let mut types = Vec::new();
+    for input in &method.sig.inputs {
+        match *input {
+            FnArg::Receiver(ref r) => receiver = Some(r),
+            FnArg::Typed(ref arg) => types.push(&*arg.ty),
+        }
+    }
+
+    let count = types.len() as i32;
+    let args = types.iter().enumerate().map(|(i, ty)| {
+        let i = i as i32;
+        quote! { <#ty as ::js_native::Argument>::from_arguments(ctx, #i)? }
+    });
+    let argc = quote! {
+        if false #(|| <#types as ::js_native::Argument>::REST)* {
+            ::js_native::class::VARARGS
+        } else {
+            #count
+        }
+    };
+
+    if opts.constructor {
+        if receiver.is_some() {
+            return Err(Error::new_spanned(
+                &method.sig,
+                "a constructor cannot take self",
+            ));
+        }
+        return Ok(Some(quote! {
+            builder.constructor((#argc, |ctx: &::js_native::DukContext, instance: &mut ::js_native::class::Instance| -> ::js_native::error::Result<i32> {
+                let value = ::js_native::class::Constructed::<#self_ty>::into_result(
+                    <#self_ty>::#ident(#(#args),*)
+                )?;
+                instance.set::<#self_ty>(value);
+                Ok(0)
+            }));
+        }));
+    }
+
+    match receiver {
+        // Associated functions stay Rust only
+        None => Ok(None),
+        Some(r) if r.reference.is_none() => Err(Error::new_spanned(
+            r,
+            "exposed methods must take self by reference",
+        )),
+        Some(_) => Ok(Some(quote! {
+            builder.method(#js_name, (#argc, |ctx: &::js_native::DukContext, instance: &mut ::js_native::class::Instance| -> ::js_native::error::Result<i32> {
+                let this = ::js_native::class::this::<#self_ty>(instance)?;
+                let ret = <#self_ty>::#ident(this, #(#args),*);
+                ::js_native::class::MethodReturn::push_return(ret, ctx)
+            }));
+        })),
+    }
+}
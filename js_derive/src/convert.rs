@@ -0,0 +1,327 @@
+//!
+//! `#[derive(ToDuktape, FromDuktape)]`
+//!
+//! Named structs are objects, tuple structs arrays and unit structs `null`.
+//! Enums are externally tagged: unit variants are their name, other
+//! variants an object with the name as single property.
+//!
+
+use super::attr::Options;
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse_quote, Data, DeriveInput, Error, Field, Fields, GenericParam, Generics, Ident, Index,
+    Lifetime, LifetimeDef, Result,
+};
+
+struct NamedField<'a> {
+    field: &'a Field,
+    key: String,
+    opts: Options,
+}
+
+fn named_fields(fields: &Fields) -> Result<Vec<NamedField<'_>>> {
+    let mut ret = Vec::new();
+    for field in fields.iter() {
+        let opts = Options::parse(&field.attrs)?;
+        let ident = field.ident.as_ref().unwrap();
+        let key = opts.rename.clone().unwrap_or_else(|| ident.to_string());
+        ret.push(NamedField { field, key, opts });
+    }
+    Ok(ret)
+}
+
+fn check_generics(generics: &Generics) -> Result<()> {
+    if let Some(lt) = generics.lifetimes().next() {
+        return Err(Error::new_spanned(
+            lt,
+            "conversions cannot be derived for types with lifetimes",
+        ));
+    }
+    Ok(())
+}
+
+pub fn derive_to(input: &DeriveInput) -> Result<TokenStream> {
+    check_generics(&input.generics)?;
+    let ident = &input.ident;
+
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param
+            .bounds
+            .push(parse_quote!(::js_native::types::ToDuktape));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let values: Vec<TokenStream> = match data.fields {
+                Fields::Named(_) => data
+                    .fields
+                    .iter()
+                    .map(|f| {
+                        let ident = &f.ident;
+                        quote!(self.#ident)
+                    })
+                    .collect(),
+                _ => (0..data.fields.len())
+                    .map(|i| {
+                        let i = Index::from(i);
+                        quote!(self.#i)
+                    })
+                    .collect(),
+            };
+            push_fields(&data.fields, &values)?
+        }
+        Data::Enum(ref data) => {
+            let mut arms = Vec::new();
+            for variant in &data.variants {
+                let opts = Options::parse(&variant.attrs)?;
+                let var_ident = &variant.ident;
+                let name = opts.rename.unwrap_or_else(|| var_ident.to_string());
+                let bindings: Vec<Ident> = (0..variant.fields.len())
+                    .map(|i| format_ident!("__field{}", i))
+                    .collect();
+                let values: Vec<TokenStream> = bindings.iter().map(|b| quote!(#b)).collect();
+
+                let arm = match variant.fields {
+                    Fields::Unit => quote! {
+                        #ident::#var_ident => {
+                            ctx.push_string(#name);
+                        }
+                    },
+                    Fields::Unnamed(ref fields) if fields.unnamed.len() == 1 => quote! {
+                        #ident::#var_ident(__field0) => {
+                            ctx.push_object();
+                            ::js_native::types::ToDuktape::to_context(__field0, ctx)?;
+                            ctx.put_prop_string(-2, #name);
+                        }
+                    },
+                    Fields::Unnamed(_) => {
+                        let push = push_fields(&variant.fields, &values)?;
+                        quote! {
+                            #ident::#var_ident(#(#bindings),*) => {
+                                ctx.push_object();
+                                #push
+                                ctx.put_prop_string(-2, #name);
+                            }
+                        }
+                    }
+                    Fields::Named(_) => {
+                        let idents = variant.fields.iter().map(|f| &f.ident);
+                        let push = push_fields(&variant.fields, &values)?;
+                        quote! {
+                            #ident::#var_ident { #(#idents: #bindings),* } => {
+                                ctx.push_object();
+                                #push
+                                ctx.put_prop_string(-2, #name);
+                            }
+                        }
+                    }
+                };
+                arms.push(arm);
+            }
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                input,
+                "ToDuktape cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::js_native::types::ToDuktape for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn to_context(self, ctx: &::js_native::DukContext) -> ::js_native::error::Result<()> {
+                #body
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Push the object or array made of `values`, one per field
+fn push_fields(fields: &Fields, values: &[TokenStream]) -> Result<TokenStream> {
+    match *fields {
+        Fields::Named(_) => {
+            let mut puts = Vec::new();
+            for (f, value) in named_fields(fields)?.into_iter().zip(values) {
+                if f.opts.skip {
+                    continue;
+                }
+                let key = &f.key;
+                puts.push(if f.opts.flatten {
+                    quote! {
+                        ::js_native::types::ToDuktape::to_context(#value, ctx)?;
+                        ::js_native::derive::merge(ctx, -2)?;
+                    }
+                } else {
+                    quote! {
+                        ::js_native::types::ToDuktape::to_context(#value, ctx)?;
+                        ctx.put_prop_string(-2, #key);
+                    }
+                });
+            }
+            Ok(quote! {
+                ctx.push_object();
+                #(#puts)*
+            })
+        }
+        Fields::Unnamed(_) => {
+            let indices = (0..values.len() as u32).collect::<Vec<_>>();
+            Ok(quote! {
+                ctx.push_array();
+                #(
+                    ::js_native::types::ToDuktape::to_context(#values, ctx)?;
+                    ctx.put_prop_index(-2, #indices);
+                )*
+            })
+        }
+        Fields::Unit => Ok(quote! {
+            ctx.push_null();
+        }),
+    }
+}
+
+pub fn derive_from(input: &DeriveInput) -> Result<TokenStream> {
+    check_generics(&input.generics)?;
+    let ident = &input.ident;
+    let name = ident.to_string();
+
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(
+            for<'__any> ::js_native::types::FromDuktape<'__any>
+        ));
+    }
+    let (_, ty_generics, where_clause) = input.generics.split_for_impl();
+    let de = Lifetime::new("'__de", Span::call_site());
+    generics
+        .params
+        .insert(0, GenericParam::Lifetime(LifetimeDef::new(de.clone())));
+    let (impl_generics, _, _) = generics.split_for_impl();
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let build = read_fields(&data.fields, quote!(#ident))?;
+            quote! {
+                let idx = ctx.normalize_index(index);
+                Ok(#build)
+            }
+        }
+        Data::Enum(ref data) => {
+            let mut unit_arms = Vec::new();
+            let mut data_arms = Vec::new();
+            for variant in &data.variants {
+                let opts = Options::parse(&variant.attrs)?;
+                let var_ident = &variant.ident;
+                let var_name = opts.rename.unwrap_or_else(|| var_ident.to_string());
+
+                match variant.fields {
+                    Fields::Unit => unit_arms.push(quote! {
+                        #var_name => Ok(#ident::#var_ident),
+                    }),
+                    Fields::Unnamed(ref fields) if fields.unnamed.len() == 1 => {
+                        let ty = &fields.unnamed[0].ty;
+                        data_arms.push(quote! {
+                            #var_name => Ok(#ident::#var_ident(
+                                ::js_native::derive::field::<#ty>(ctx, idx, #var_name)?
+                            )),
+                        });
+                    }
+                    _ => {
+                        let build = read_fields(&variant.fields, quote!(#ident::#var_ident))?;
+                        data_arms.push(quote! {
+                            #var_name => {
+                                ctx.get_prop_string(idx, #var_name);
+                                let ret = (|| -> ::js_native::error::Result<Self> {
+                                    let idx = ctx.normalize_index(-1);
+                                    Ok(#build)
+                                })();
+                                ctx.pop(1);
+                                ret.map_err(|e| ::js_native::derive::context(e, #var_name))
+                            }
+                        });
+                    }
+                }
+            }
+            quote! {
+                let idx = ctx.normalize_index(index);
+                let variant = ::js_native::derive::variant(ctx, idx)?;
+                if ctx.is_string(idx) {
+                    return match variant.as_str() {
+                        #(#unit_arms)*
+                        v => Err(::js_native::derive::unknown_variant(v)),
+                    };
+                }
+                match variant.as_str() {
+                    #(#data_arms)*
+                    v => Err(::js_native::derive::unknown_variant(v)),
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                input,
+                "FromDuktape cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::js_native::types::FromDuktape<#de> for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn from_context(
+                ctx: &#de ::js_native::DukContext,
+                index: ::js_native::Idx,
+            ) -> ::js_native::error::Result<Self> {
+                (|| -> ::js_native::error::Result<Self> { #body })()
+                    .map_err(|e| ::js_native::derive::root(e, #name))
+            }
+        }
+    })
+}
+
+/// Build `path` from the object or array at `idx`
+fn read_fields(fields: &Fields, path: TokenStream) -> Result<TokenStream> {
+    match *fields {
+        Fields::Named(_) => {
+            let mut inits = Vec::new();
+            for f in named_fields(fields)? {
+                let ident = &f.field.ident;
+                let ty = &f.field.ty;
+                let key = &f.key;
+                inits.push(if f.opts.skip {
+                    quote!(#ident: ::std::default::Default::default())
+                } else if f.opts.flatten {
+                    quote!(#ident: <#ty as ::js_native::types::FromDuktape>::from_context(ctx, idx)?)
+                } else if f.opts.default {
+                    quote!(#ident: ::js_native::derive::field_or_default::<#ty>(ctx, idx, #key)?)
+                } else {
+                    quote!(#ident: ::js_native::derive::field::<#ty>(ctx, idx, #key)?)
+                });
+            }
+            Ok(quote! {{
+                ::js_native::derive::expect(ctx, idx, ::js_native::types::Type::Object)?;
+                #path { #(#inits),* }
+            }})
+        }
+        Fields::Unnamed(_) => {
+            let inits = fields.iter().enumerate().map(|(i, f)| {
+                let ty = &f.ty;
+                let i = i as u32;
+                quote!(::js_native::derive::element::<#ty>(ctx, idx, #i)?)
+            });
+            Ok(quote! {{
+                ::js_native::derive::expect(ctx, idx, ::js_native::types::Type::Array)?;
+                #path ( #(#inits),* )
+            }})
+        }
+        Fields::Unit => Ok(path),
+    }
+}
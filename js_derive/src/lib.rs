@@ -0,0 +1,67 @@
+//!
+//! Derive macros for js_native
+//!
+//! Use them through the `derive` feature of js_native, which re-exports them.
+//!
+
+extern crate proc_macro;
+
+mod attr;
+mod class;
+mod convert;
+
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, DeriveInput, ItemImpl};
+
+/// Expose a struct as a JS class
+///
+/// Fields marked `#[js(get)]` and/or `#[js(set)]` become accessor
+/// properties, `#[js(rename = "...")]` changes their name. The class name
+/// defaults to the struct name, and can be set with `#[js(name = "...")]`.
+/// The constructor and methods come from a `#[js_methods]` impl block.
+#[proc_macro_derive(JsClass, attributes(js))]
+pub fn derive_js_class(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    class::derive(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Expose the methods of an impl block on the class of a `#[derive(JsClass)]`
+///
+/// Every method taking `self` by reference is exposed, unless marked
+/// `#[js(skip)]`. The function marked `#[js(constructor)]` is called by
+/// `new` and returns either `Self` or `Result<Self>`. Arguments are
+/// converted with `js_native::Argument`.
+#[proc_macro_attribute]
+pub fn js_methods(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemImpl);
+    class::methods(item)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Convert a struct or enum into a JS value
+///
+/// Named structs become objects, tuple structs arrays, unit variants
+/// strings and other variants `{ "Variant": value }`. Fields accept
+/// `#[js(rename = "...")]`, `#[js(skip)]` and `#[js(flatten)]`.
+#[proc_macro_derive(ToDuktape, attributes(js))]
+pub fn derive_to_duktape(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    convert::derive_to(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Convert a JS value into a struct or enum, the inverse of `ToDuktape`
+///
+/// Skipped fields, and missing `#[js(default)]` ones, use
+/// `Default::default()`.
+#[proc_macro_derive(FromDuktape, attributes(js))]
+pub fn derive_from_duktape(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    convert::derive_from(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
@@ -1,8 +1,12 @@
-use super::ctx::DukContext;
-use super::error::Result;
-use crate::types::ToDuktape;
+use super::ctx::{DukContext, Idx};
+use super::error::{Error, ErrorKind, Result};
+use super::heap;
+use crate::privates::DUK_VARARGS;
+use crate::types::{FromDuktape, ToDuktape};
 use dukbind::*;
-use std::ffi::{c_void, CString};
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
 static KEY: &'static [u8] = b"\xFFptr";
 pub trait Callable {
     /// Specify how many arguments the function accepts
@@ -22,24 +26,22 @@ unsafe extern "C" fn call(ctx: *mut duk_context) -> duk_ret_t {
     let ptr = duk_get_pointer(ctx, -1) as *mut Box<dyn Callable>;
     let pp = Box::from_raw(ptr);
     duk_pop_2(ctx);
-    let ret = match pp.call(&mut c) {
-        Err(e) => {
-            duk_error_raw(
-                ctx,
-                DUK_ERR_ERROR as i32,
-                "".as_ptr() as *const i8,
-                0,
-                CString::new(format!("{}", e.0)).unwrap().as_ptr(),
-            );
-            -1
-        }
-        Ok(ret) => ret,
-    };
+    let base = duk_get_top(ctx);
+    let ret = pp
+        .call(&mut c)
+        .and_then(|ret| heap::check_stack(ctx, base, ret));
 
     // It should not be dropped
     Box::into_raw(pp);
 
-    return ret;
+    match ret {
+        Err(e) => {
+            c.push_error(e);
+            duk_throw_raw(ctx);
+            -1
+        }
+        Ok(ret) => ret,
+    }
 }
 
 unsafe extern "C" fn dtor(ctx: *mut duk_context) -> duk_ret_t {
@@ -100,3 +102,202 @@ impl<T: 'static + Fn(&DukContext) -> Result<i32>> ToDuktape for (i32, T) {
         Ok(())
     }
 }
+
+/// A plain Rust function, called with its arguments converted from the stack
+///
+/// ```ignore
+/// ctx.push_function(typed(|a: f64, b: Option<f64>| Ok(a + b.unwrap_or(1.0))));
+/// ```
+pub struct Typed<F, Args> {
+    func: F,
+    args: PhantomData<fn(Args)>,
+}
+
+/// Wrap `func` into a `Callable`. Arguments must implement `Argument`,
+/// and the return value `ToDuktape`.
+pub fn typed<F, Args>(func: F) -> Typed<F, Args>
+where
+    Typed<F, Args>: Callable,
+{
+    Typed {
+        func,
+        args: PhantomData,
+    }
+}
+
+/// Converts the argument at a given index of a typed function
+pub trait Argument: Sized {
+    /// Whether the argument takes all the remaining ones, only allowed last
+    const REST: bool = false;
+
+    fn from_arguments(ctx: &DukContext, index: Idx) -> Result<Self>;
+}
+
+impl<T> Argument for T
+where
+    T: for<'de> FromDuktape<'de>,
+{
+    fn from_arguments(ctx: &DukContext, index: Idx) -> Result<Self> {
+        // Missing arguments are only padded by duktape when argc is fixed
+        let ret = if index >= ctx.top() {
+            ctx.push_undefined();
+            let ret = T::from_context(ctx, -1);
+            ctx.pop(1);
+            ret
+        } else {
+            T::from_context(ctx, index)
+        };
+        ret.map_err(|e| argument_error(index, e))
+    }
+}
+
+/// The remaining arguments of a typed function, only allowed last
+///
+/// ```compile_fail
+/// # use js_native::{typed, DukContext, Rest};
+/// let ctx = DukContext::new().unwrap();
+/// ctx.push_function(typed(|rest: Rest<f64>, last: f64| Ok(rest.len() as f64 + last)));
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Rest<T>(pub Vec<T>);
+
+impl<T> Deref for Rest<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Rest<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.0
+    }
+}
+
+impl<T> Argument for Rest<T>
+where
+    T: for<'de> FromDuktape<'de>,
+{
+    const REST: bool = true;
+
+    fn from_arguments(ctx: &DukContext, index: Idx) -> Result<Self> {
+        let mut ret = Vec::new();
+        for i in index..ctx.top() {
+            ret.push(T::from_context(ctx, i).map_err(|e| argument_error(i, e))?);
+        }
+        Ok(Rest(ret))
+    }
+}
+
+fn argument_error(index: Idx, e: Error) -> Error {
+    let message = match *e.kind() {
+        ErrorKind::TypeError(ref m) | ErrorKind::Error(ref m) => m.clone(),
+        ref k => k.to_string(),
+    };
+    ErrorKind::TypeError(format!("argument {}: {}", index, message)).into()
+}
+
+macro_rules! impl_typed {
+    ($argc: expr; $($arg: ident $idx: expr),*) => {
+        impl<F, $($arg),*> Typed<F, ($($arg,)*)>
+        where
+            $($arg: Argument,)*
+        {
+            /// Fails the build when `Rest` is not the last argument
+            const REST_LAST: () = assert!(
+                true $(&& (!$arg::REST || $idx + 1 == $argc))*,
+                "only the last argument of a typed function may be `Rest`"
+            );
+        }
+
+        impl<F, R, $($arg),*> Callable for Typed<F, ($($arg,)*)>
+        where
+            F: Fn($($arg),*) -> Result<R>,
+            R: ToDuktape,
+            $($arg: Argument,)*
+        {
+            fn argc(&self) -> i32 {
+                let () = Self::REST_LAST;
+                if false $(|| $arg::REST)* {
+                    DUK_VARARGS
+                } else {
+                    $argc
+                }
+            }
+
+            fn call(&self, ctx: &DukContext) -> Result<i32> {
+                let ret = (self.func)($($arg::from_arguments(ctx, $idx)?),*)?;
+                ctx.push(ret)?;
+                Ok(1)
+            }
+        }
+
+        impl<F, R, $($arg),*> ToDuktape for Typed<F, ($($arg,)*)>
+        where
+            F: 'static + Fn($($arg),*) -> Result<R>,
+            R: 'static + ToDuktape,
+            $($arg: 'static + Argument,)*
+        {
+            fn to_context(self, ctx: &DukContext) -> Result<()> {
+                let boxed: Box<dyn Callable> = Box::new(self);
+                unsafe { push_callable(ctx, boxed) };
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_typed!(0;);
+impl_typed!(1; A1 0);
+impl_typed!(2; A1 0, A2 1);
+impl_typed!(3; A1 0, A2 1, A3 2);
+impl_typed!(4; A1 0, A2 1, A3 2, A4 3);
+impl_typed!(5; A1 0, A2 1, A3 2, A4 3, A5 4);
+impl_typed!(6; A1 0, A2 1, A3 2, A4 3, A5 4, A6 5);
+impl_typed!(7; A1 0, A2 1, A3 2, A4 3, A5 4, A6 5, A7 6);
+impl_typed!(8; A1 0, A2 1, A3 2, A4 3, A5 4, A6 5, A7 6, A8 7);
+
+#[cfg(test)]
+mod test {
+    use super::{typed, Rest};
+    use crate::ctx::DukContext;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn typed_functions() {
+        let ctx = DukContext::new().unwrap();
+        ctx.push_global_object()
+            .push_function(typed(|a: f64, b: Option<f64>| Ok(a + b.unwrap_or(1.0))))
+            .put_prop_string(-2, "add")
+            .push_function(typed(|sep: String, parts: Rest<String>| {
+                Ok(parts.join(&sep))
+            }))
+            .put_prop_string(-2, "join")
+            .push_function(typed(|| Ok(())))
+            .put_prop_string(-2, "nothing")
+            .pop(1);
+
+        let out: f64 = ctx.eval("add(1, 2) + add(3)").unwrap().getp().unwrap();
+        assert_eq!(out, 7.0);
+
+        let out: String = ctx
+            .eval("join('-', 'a', 'b', 'c') + join(',')")
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert_eq!(out, "a-b-c");
+
+        let undefined: bool = ctx.eval("nothing() === undefined").unwrap().getp().unwrap();
+        assert!(undefined);
+
+        match ctx.eval("add('x')").unwrap_err().kind() {
+            ErrorKind::TypeError(m) => assert!(m.starts_with("argument 0: "), "{}", m),
+            k => panic!("unexpected error: {}", k),
+        }
+        match ctx.eval("join('-', 'a', 2)").unwrap_err().kind() {
+            ErrorKind::TypeError(m) => assert!(m.starts_with("argument 2: "), "{}", m),
+            k => panic!("unexpected error: {}", k),
+        }
+    }
+}
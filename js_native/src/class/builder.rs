@@ -11,6 +11,10 @@ use std::ffi::c_void;
 
 pub enum Prototype {
     Method(Box<dyn Method>),
+    Property {
+        getter: Option<Box<dyn Method>>,
+        setter: Option<Box<dyn Method>>,
+    },
 }
 
 #[derive(Default)]
@@ -38,6 +42,43 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Define an accessor property, the getter is called without arguments
+    pub fn getter<T: 'static + Method>(&mut self, name: &str, getter: T) -> &mut Self {
+        let b: Box<dyn Method> = Box::new(getter);
+        match self.property(name) {
+            Prototype::Property { getter, .. } => *getter = Some(b),
+            _ => unreachable!(),
+        }
+        self
+    }
+
+    /// Define an accessor property, the setter is called with the new value
+    pub fn setter<T: 'static + Method>(&mut self, name: &str, setter: T) -> &mut Self {
+        let b: Box<dyn Method> = Box::new(setter);
+        match self.property(name) {
+            Prototype::Property { setter, .. } => *setter = Some(b),
+            _ => unreachable!(),
+        }
+        self
+    }
+
+    fn property(&mut self, name: &str) -> &mut Prototype {
+        let prop = self
+            .methods
+            .entry(name.to_owned())
+            .or_insert(Prototype::Property {
+                getter: None,
+                setter: None,
+            });
+        if let Prototype::Method(_) = prop {
+            *prop = Prototype::Property {
+                getter: None,
+                setter: None,
+            };
+        }
+        prop
+    }
+
     pub fn constructor<T: 'static + Method>(&mut self, ctor: T) -> &mut Self {
         let b: Box<dyn Method> = Box::new(ctor);
         self.ctor = Some(b);
@@ -84,6 +125,20 @@ pub(crate) unsafe fn push_class_builder(ctx: &DukContext, builder: Builder) -> R
                 push_method(ctx, m);
                 ctx.put_prop_string(-2, &name);
             }
+            Prototype::Property { getter, setter } => {
+                let proto = ctx.normalize_index(-1);
+                let mut flags = DUK_DEFPROP_HAVE_CONFIGURABLE | DUK_DEFPROP_CONFIGURABLE;
+                ctx.push_string(&name);
+                if let Some(getter) = getter {
+                    push_method(ctx, getter);
+                    flags |= DUK_DEFPROP_HAVE_GETTER;
+                }
+                if let Some(setter) = setter {
+                    push_method(ctx, setter);
+                    flags |= DUK_DEFPROP_HAVE_SETTER;
+                }
+                duk_def_prop(ctx.inner, proto, flags);
+            }
         }
     }
 
@@ -125,13 +180,9 @@ unsafe extern "C" fn class_ctor(ctx: *mut duk_context) -> duk_ret_t {
             Ok(_) => {}
             Err(e) => {
                 Box::into_raw(ctor);
-                duk_error_raw(
-                    ctx,
-                    DUK_ERR_ERROR as i32,
-                    "".as_ptr() as *const i8,
-                    0,
-                    format!("ctor call failed: {}", e).as_ptr() as *const i8,
-                );
+                drop(instance);
+                c.push_error(e);
+                duk_throw_raw(ctx);
                 return 0;
             }
         };
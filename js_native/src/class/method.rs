@@ -1,8 +1,13 @@
-use super::super::{ctx::DukContext, error::Result};
+use super::super::{
+    ctx::DukContext,
+    error::{Error, Result},
+};
+use crate::heap;
 use crate::privates::DUK_VARARGS;
 use dukbind::*;
-use std::ffi::{c_void, CString};
-use typemap::TypeMap;
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use typemap::{Key, TypeMap};
 
 pub struct Instance {
     types: TypeMap,
@@ -22,6 +27,25 @@ impl Instance {
     pub fn data_mut(&mut self) -> &mut TypeMap {
         &mut self.types
     }
+
+    /// Store the Rust value backing the instance
+    pub fn set<T: 'static>(&mut self, value: T) {
+        self.types.insert::<Payload<T>>(value);
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.types.get::<Payload<T>>()
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.types.get_mut::<Payload<T>>()
+    }
+}
+
+struct Payload<T>(PhantomData<T>);
+
+impl<T: 'static> Key for Payload<T> {
+    type Value = T;
 }
 
 static KEY: &'static [u8] = b"\xFFmethod_ptr";
@@ -78,13 +102,8 @@ unsafe extern "C" fn call(ctx: *mut duk_context) -> duk_ret_t {
     if duk_has_prop_lstring(ctx, -1, DATA_KEY.as_ptr() as *const i8, DATA_KEY.len()) != 1 {
         // Keep it
         Box::into_raw(method);
-        duk_error_raw(
-            ctx,
-            DUK_ERR_ERROR as i32,
-            "".as_ptr() as *const i8,
-            0,
-            "could find data ptr".as_ptr() as *const i8,
-        );
+        c.push_error(Error::type_err("could not find data ptr"));
+        duk_throw_raw(ctx);
         return 0;
     }
 
@@ -93,18 +112,17 @@ unsafe extern "C" fn call(ctx: *mut duk_context) -> duk_ret_t {
     let mut pp = Box::from_raw(ptr);
     duk_pop(ctx);
 
-    let ret = match method.call(&mut c, &mut pp) {
+    let base = duk_get_top(ctx);
+    let ret = match method
+        .call(&mut c, &mut pp)
+        .and_then(|ret| heap::check_stack(ctx, base, ret))
+    {
         Err(e) => {
             // Keep it
             Box::into_raw(method);
             Box::into_raw(pp);
-            duk_error_raw(
-                ctx,
-                DUK_ERR_ERROR as i32,
-                "".as_ptr() as *const i8,
-                0,
-                CString::new(format!("{}", e.0)).unwrap().as_ptr(),
-            );
+            c.push_error(e);
+            duk_throw_raw(ctx);
             return 0;
         }
         Ok(ret) => ret,
@@ -1,8 +1,10 @@
 mod builder;
 mod method;
+mod typed;
 
 pub use self::builder::*;
 pub use self::method::{Instance, Method};
+pub use self::typed::*;
 
 pub fn build<'a>() -> Builder<'a> {
     Builder::default()
@@ -12,6 +14,7 @@ pub fn build<'a>() -> Builder<'a> {
 pub mod tests {
 
     use super::super::ctx::DukContext;
+    use super::super::error::Error;
     use super::super::types::Object;
     use super::method::Instance;
     #[test]
@@ -40,4 +43,103 @@ pub mod tests {
         let greeting = out.call::<_, _, String>("testMethodNoArg", ()).unwrap();
         assert_eq!(greeting, "Hello, World!");
     }
+
+    #[test]
+    fn class_typed_errors() {
+        let ctx = DukContext::new().unwrap();
+
+        let mut b = super::build();
+        b.method("fail", |_ctx: &DukContext, _this: &mut Instance| {
+            Err(Error::type_err("bad argument"))
+        });
+
+        ctx.push_global_object();
+        ctx.push_class(b).unwrap();
+        ctx.put_prop_string(-2, "Test").pop(1);
+
+        let ok: bool = ctx
+            .eval(
+                "try { new Test().fail(); false } \
+                 catch (e) { e instanceof TypeError && e.message === 'bad argument' }",
+            )
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert!(ok);
+    }
+
+    #[cfg(feature = "derive")]
+    mod derive {
+        use crate::ctx::DukContext;
+        use crate::error::{Error, Result};
+        use crate::{js_methods, JsClass};
+
+        #[derive(JsClass)]
+        #[js(name = "Point")]
+        struct Vec2 {
+            #[js(get, set)]
+            x: f64,
+            #[js(get, rename = "ordinate")]
+            y: f64,
+        }
+
+        #[js_methods]
+        impl Vec2 {
+            #[js(constructor)]
+            fn new(x: f64, y: Option<f64>) -> Result<Vec2> {
+                if x < 0.0 {
+                    return Err(Error::range_err("negative"));
+                }
+                Ok(Vec2 {
+                    x,
+                    y: y.unwrap_or(0.0),
+                })
+            }
+
+            fn length(&self) -> f64 {
+                (self.x * self.x + self.y * self.y).sqrt()
+            }
+
+            #[js(rename = "scaleBy")]
+            fn scale(&mut self, factor: f64) -> Result<()> {
+                self.x *= factor;
+                self.y *= factor;
+                Ok(())
+            }
+
+            #[js(skip)]
+            #[allow(dead_code)]
+            fn hidden(&self) {}
+        }
+
+        #[test]
+        fn derive_class() {
+            let ctx = DukContext::new().unwrap();
+            ctx.push_global_object();
+            ctx.push_class(super::super::of::<Vec2>()).unwrap();
+            ctx.put_prop_string(-2, "Point").pop(1);
+
+            let out: String = ctx
+                .eval(
+                    r#"
+                    var p = new Point(3, 4);
+                    var before = p.length();
+                    p.scaleBy(2);
+                    p.x = 0;
+                    [Point.name, before, p.x, p.ordinate, typeof p.hidden].join()
+                    "#,
+                )
+                .unwrap()
+                .getp()
+                .unwrap();
+            assert_eq!(out, "Point,5,0,8,undefined");
+
+            let ok: bool = ctx
+                .eval("try { new Point(-1); false } catch (e) { e instanceof RangeError }")
+                .unwrap()
+                .getp()
+                .unwrap();
+            assert!(ok);
+        }
+    }
 }
@@ -0,0 +1,80 @@
+//!
+//! Classes backed by a Rust type
+//!
+//! The value is stored in the `Instance` of every object, and handed to
+//! methods as `&mut Self`. `#[derive(JsClass)]` and `#[js_methods]` from the
+//! `derive` feature implement these traits.
+//!
+
+use super::{Builder, Instance};
+use crate::ctx::DukContext;
+use crate::error::{Error, Result};
+pub use crate::privates::DUK_VARARGS as VARARGS;
+use crate::types::ToDuktape;
+
+pub trait JsClass: 'static + Sized {
+    /// Name of the class constructor
+    const NAME: &'static str;
+
+    /// Define the accessor properties of the class
+    fn properties(builder: &mut Builder);
+}
+
+pub trait JsMethods: JsClass {
+    /// Define the constructor and methods of the class
+    fn methods(builder: &mut Builder);
+}
+
+/// Builder of the class exposing `T`
+pub fn of<'a, T: JsMethods>() -> Builder<'a> {
+    let mut b = Builder::default();
+    b.name(T::NAME);
+    T::properties(&mut b);
+    T::methods(&mut b);
+    b
+}
+
+/// The value of type `T` backing an instance
+pub fn this<T: JsClass>(instance: &mut Instance) -> Result<&mut T> {
+    match instance.get_mut::<T>() {
+        Some(this) => Ok(this),
+        None => Err(Error::type_err(format!("not an initialized {}", T::NAME))),
+    }
+}
+
+/// Return values of methods, either a value or a `Result` of one
+pub trait MethodReturn {
+    /// Push the value, returning the number of values pushed
+    fn push_return(self, ctx: &DukContext) -> Result<i32>;
+}
+
+impl<T: ToDuktape> MethodReturn for T {
+    fn push_return(self, ctx: &DukContext) -> Result<i32> {
+        ctx.push(self)?;
+        Ok(1)
+    }
+}
+
+impl<T: ToDuktape> MethodReturn for Result<T> {
+    fn push_return(self, ctx: &DukContext) -> Result<i32> {
+        ctx.push(self?)?;
+        Ok(1)
+    }
+}
+
+/// Return values of constructors, either `Self` or `Result<Self>`
+pub trait Constructed<T> {
+    fn into_result(self) -> Result<T>;
+}
+
+impl<T: JsClass> Constructed<T> for T {
+    fn into_result(self) -> Result<T> {
+        Ok(self)
+    }
+}
+
+impl<T: JsClass> Constructed<T> for Result<T> {
+    fn into_result(self) -> Result<T> {
+        self
+    }
+}
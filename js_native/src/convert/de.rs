@@ -0,0 +1,462 @@
+use super::{type_name, Error, Result};
+use crate::ctx::{DukContext, Enumerate, Idx};
+use crate::types::Type;
+use serde::de::{
+    self, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess,
+    Visitor,
+};
+use serde::forward_to_deserialize_any;
+
+/// Reads the value at an index of the stack of a context
+#[derive(Clone, Copy)]
+pub struct Deserializer<'a> {
+    ctx: &'a DukContext,
+    idx: Idx,
+}
+
+impl<'a> Deserializer<'a> {
+    pub fn new(ctx: &'a DukContext, idx: Idx) -> Deserializer<'a> {
+        Deserializer {
+            ctx,
+            idx: ctx.normalize_index(idx),
+        }
+    }
+
+    fn expected(&self, what: &str) -> Error {
+        Error::new(format!(
+            "expected {}, got {}",
+            what,
+            type_name(self.ctx, self.idx)
+        ))
+    }
+
+    fn is(&self, ty: Type) -> bool {
+        !self.ctx.is_buffer(self.idx) && self.ctx.get_type(self.idx) == ty
+    }
+
+    fn is_nullish(&self) -> bool {
+        self.is(Type::Null) || self.is(Type::Undefined)
+    }
+
+    /// Integral numbers are visited as integers, so integer types accept them
+    fn number<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if !self.is(Type::Number) {
+            return Err(self.expected("number"));
+        }
+        let n = self.ctx.get_number(self.idx)?;
+        if n.fract() == 0.0 && n >= -9_223_372_036_854_775_808.0 && n < 9_223_372_036_854_775_808.0
+        {
+            visitor.visit_i64(n as i64)
+        } else if n.fract() == 0.0 && n >= 0.0 && n < 18_446_744_073_709_551_616.0 {
+            visitor.visit_u64(n as u64)
+        } else {
+            visitor.visit_f64(n)
+        }
+    }
+}
+
+macro_rules! deserialize_number {
+    ($($func: ident),*) => {
+        $(
+            fn $func<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                self.number(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.ctx.is_buffer(self.idx) {
+            return visitor.visit_bytes(self.ctx.get_bytes(self.idx)?);
+        }
+        match self.ctx.get_type(self.idx) {
+            Type::Undefined | Type::Null => visitor.visit_unit(),
+            Type::Boolean => visitor.visit_bool(self.ctx.get_boolean(self.idx)?),
+            Type::Number => self.number(visitor),
+            Type::String => visitor.visit_str(self.ctx.get_string(self.idx)?),
+            Type::Array => self.deserialize_seq(visitor),
+            Type::Object => self.deserialize_map(visitor),
+            Type::Function | Type::Buffer => Err(Error::new(format!(
+                "cannot deserialize a {}",
+                type_name(self.ctx, self.idx)
+            ))),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if !self.is(Type::Boolean) {
+            return Err(self.expected("boolean"));
+        }
+        visitor.visit_bool(self.ctx.get_boolean(self.idx)?)
+    }
+
+    deserialize_number!(
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_f32,
+        deserialize_f64
+    );
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if !self.is(Type::String) {
+            return Err(self.expected("string"));
+        }
+        visitor.visit_str(self.ctx.get_string(self.idx)?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.ctx.is_buffer(self.idx) {
+            return visitor.visit_bytes(self.ctx.get_bytes(self.idx)?);
+        }
+        if self.is(Type::Array) {
+            return self.deserialize_seq(visitor);
+        }
+        Err(self.expected("buffer"))
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.is_nullish() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if !self.is_nullish() {
+            return Err(self.expected("null"));
+        }
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if !self.is(Type::Array) {
+            return Err(self.expected("array"));
+        }
+        visitor.visit_seq(Seq {
+            ctx: self.ctx,
+            idx: self.idx,
+            index: 0,
+            len: self.ctx.get_length(self.idx),
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if !self.is(Type::Object) {
+            return Err(self.expected("object"));
+        }
+        self.ctx
+            .enumerator(self.idx, Enumerate::OWN_PROPERTIES_ONLY)?;
+        let enum_idx = self.ctx.normalize_index(-1);
+        visitor.visit_map(Map {
+            ctx: self.ctx,
+            enum_idx,
+            key: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        if self.is(Type::String) {
+            let variant = self.ctx.get_string(self.idx)?.to_owned();
+            return visitor.visit_enum(Enum {
+                ctx: self.ctx,
+                variant,
+                value: None,
+            });
+        }
+        if !self.is(Type::Object) {
+            return Err(self.expected("string or object"));
+        }
+
+        let mut keys = Vec::new();
+        self.ctx
+            .enumerator(self.idx, Enumerate::OWN_PROPERTIES_ONLY)?;
+        while self.ctx.next(-1, false)? {
+            keys.push(self.ctx.get_string(-1)?.to_owned());
+            self.ctx.pop(1);
+        }
+        self.ctx.pop(1);
+        if keys.len() != 1 {
+            return Err(self.expected("object with a single variant key"));
+        }
+
+        let variant = keys.remove(0);
+        self.ctx.get_prop_string(self.idx, &variant);
+        let value = self.ctx.normalize_index(-1);
+        let ret = visitor.visit_enum(Enum {
+            ctx: self.ctx,
+            variant: variant.clone(),
+            value: Some(value),
+        });
+        self.ctx.pop(1);
+        ret.map_err(|e| e.at_key(&variant))
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+}
+
+struct Seq<'a> {
+    ctx: &'a DukContext,
+    idx: Idx,
+    index: usize,
+    len: usize,
+}
+
+impl<'de, 'a> SeqAccess<'de> for Seq<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.index >= self.len {
+            return Ok(None);
+        }
+        let index = self.index;
+        self.index += 1;
+
+        self.ctx.get_prop_index(self.idx, index as u32);
+        let ret = seed.deserialize(Deserializer::new(self.ctx, -1));
+        self.ctx.pop(1);
+        ret.map(Some).map_err(|e| e.at_index(index))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.index)
+    }
+}
+
+/// Own properties of an object, the enumerator being at `enum_idx`
+struct Map<'a> {
+    ctx: &'a DukContext,
+    enum_idx: Idx,
+    key: Option<String>,
+}
+
+impl<'de, 'a> MapAccess<'de> for Map<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if !self.ctx.next(self.enum_idx, true)? {
+            self.ctx.pop(1);
+            return Ok(None);
+        }
+        let key = self.ctx.get_string(-2)?.to_owned();
+        let ret = seed
+            .deserialize(Key(key.clone()))
+            .map_err(|e| e.at_key(&key));
+        self.key = Some(key);
+        ret.map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let key = self.key.take().unwrap_or_default();
+        let ret = seed.deserialize(Deserializer::new(self.ctx, -1));
+        self.ctx.pop(2);
+        ret.map_err(|e| e.at_key(&key))
+    }
+}
+
+struct Enum<'a> {
+    ctx: &'a DukContext,
+    variant: String,
+    /// Index of the variant value, `None` for unit variants given as strings
+    value: Option<Idx>,
+}
+
+impl<'de, 'a> EnumAccess<'de> for Enum<'a> {
+    type Error = Error;
+    type Variant = Variant<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Variant<'a>)> {
+        let ret = seed.deserialize(Key(self.variant))?;
+        Ok((
+            ret,
+            Variant {
+                ctx: self.ctx,
+                value: self.value,
+            },
+        ))
+    }
+}
+
+struct Variant<'a> {
+    ctx: &'a DukContext,
+    value: Option<Idx>,
+}
+
+impl<'a> Variant<'a> {
+    fn value(&self) -> Result<Deserializer<'a>> {
+        match self.value {
+            Some(idx) => Ok(Deserializer::new(self.ctx, idx)),
+            None => Err(Error::new("expected an object for a variant with data")),
+        }
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for Variant<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.value {
+            None => Ok(()),
+            Some(idx) => {
+                de::Deserializer::deserialize_unit(Deserializer::new(self.ctx, idx), de::IgnoredAny)
+                    .map(|_| ())
+            }
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self.value()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self.value()?, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self.value()?, visitor)
+    }
+}
+
+/// Property names, parsed when a map has non-string keys
+struct Key(String);
+
+impl Key {
+    fn parse<T: std::str::FromStr>(&self, what: &str) -> Result<T> {
+        self.0
+            .parse()
+            .map_err(|_| Error::new(format!("expected {} key, got '{}'", what, self.0)))
+    }
+}
+
+macro_rules! deserialize_key {
+    ($($func: ident => $visit: ident: $T: ty),*) => {
+        $(
+            fn $func<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                visitor.$visit(self.parse::<$T>(stringify!($T))?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Key {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_string(self.0)
+    }
+
+    deserialize_key!(
+        deserialize_bool => visit_bool: bool,
+        deserialize_i8 => visit_i8: i8,
+        deserialize_i16 => visit_i16: i16,
+        deserialize_i32 => visit_i32: i32,
+        deserialize_i64 => visit_i64: i64,
+        deserialize_u8 => visit_u8: u8,
+        deserialize_u16 => visit_u16: u16,
+        deserialize_u32 => visit_u32: u32,
+        deserialize_u64 => visit_u64: u64,
+        deserialize_f32 => visit_f32: f32,
+        deserialize_f64 => visit_f64: f64,
+        deserialize_char => visit_char: char
+    );
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct seq tuple tuple_struct map
+        struct identifier ignored_any
+    }
+}
@@ -0,0 +1,276 @@
+//!
+//! Serde support
+//!
+//! `Serializer` pushes any `Serialize` value onto the value stack, and
+//! `Deserializer` reads any `Deserialize` value from it. Enums use the
+//! same externally tagged layout as `#[derive(ToDuktape, FromDuktape)]`.
+//!
+
+mod de;
+mod ser;
+
+pub use self::de::Deserializer;
+pub use self::ser::Serializer;
+
+use crate::ctx::{DukContext, Idx};
+use crate::error::{self, ErrorKind};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::{self, Display};
+
+/// Push `value` onto the stack
+pub fn to_value<T: Serialize + ?Sized>(ctx: &DukContext, value: &T) -> error::Result<()> {
+    let top = ctx.top();
+    match value.serialize(Serializer::new(ctx)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            ctx.pop(ctx.top() - top);
+            Err(e.into())
+        }
+    }
+}
+
+/// Read a value of type `T` from `idx`
+pub fn from_value<T: DeserializeOwned>(ctx: &DukContext, idx: Idx) -> error::Result<T> {
+    let idx = ctx.normalize_index(idx);
+    let top = ctx.top();
+    let ret = T::deserialize(Deserializer::new(ctx, idx));
+    // Errors may leave values behind
+    ctx.pop(ctx.top() - top);
+    Ok(ret?)
+}
+
+#[derive(Debug)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// A conversion error, with the path of the value it happened at
+#[derive(Debug)]
+pub struct Error {
+    /// Innermost segment first
+    path: Vec<Segment>,
+    message: String,
+}
+
+impl Error {
+    fn new<T: Display>(message: T) -> Error {
+        Error {
+            path: Vec::new(),
+            message: message.to_string(),
+        }
+    }
+
+    fn at_key(mut self, key: &str) -> Error {
+        self.path.push(Segment::Key(key.to_owned()));
+        self
+    }
+
+    fn at_index(mut self, index: usize) -> Error {
+        self.path.push(Segment::Index(index));
+        self
+    }
+
+    /// Path of the value, e.g. `players[3].name`
+    pub fn path(&self) -> String {
+        let mut ret = String::new();
+        for segment in self.path.iter().rev() {
+            match *segment {
+                Segment::Key(ref key) => {
+                    if !ret.is_empty() {
+                        ret.push('.');
+                    }
+                    ret.push_str(key);
+                }
+                Segment::Index(i) => ret.push_str(&format!("[{}]", i)),
+            }
+        }
+        ret
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path(), self.message)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl serde::ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::new(msg)
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::new(msg)
+    }
+}
+
+impl From<error::Error> for Error {
+    fn from(e: error::Error) -> Error {
+        match *e.kind() {
+            ErrorKind::TypeError(ref m) => Error::new(m),
+            ref k => Error::new(k),
+        }
+    }
+}
+
+impl From<Error> for error::Error {
+    fn from(e: Error) -> error::Error {
+        ErrorKind::TypeError(e.to_string()).into()
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Name of the type of the value at `idx`, for error messages
+fn type_name(ctx: &DukContext, idx: Idx) -> &'static str {
+    if ctx.is_buffer(idx) {
+        return "buffer";
+    }
+    match ctx.get_type(idx) {
+        crate::types::Type::Undefined => "undefined",
+        crate::types::Type::Null => "null",
+        crate::types::Type::String => "string",
+        crate::types::Type::Boolean => "boolean",
+        crate::types::Type::Number => "number",
+        crate::types::Type::Object => "object",
+        crate::types::Type::Array => "array",
+        crate::types::Type::Function => "function",
+        crate::types::Type::Buffer => "buffer",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_value, to_value};
+    use crate::ctx::DukContext;
+    use serde_derive::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Role {
+        Admin,
+        Guest(String),
+        Member { level: u8 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Player {
+        name: String,
+        score: Option<u32>,
+        role: Role,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Config {
+        players: Vec<Player>,
+        ids: BTreeMap<u32, (i64, f64)>,
+        #[serde(with = "bytes")]
+        data: Vec<u8>,
+    }
+
+    /// Serialize the data as a buffer rather than an array
+    mod bytes {
+        use serde::de::{Deserializer, Visitor};
+        use serde::Serializer;
+        use std::fmt;
+
+        pub fn serialize<S: Serializer>(v: &[u8], s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_bytes(v)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+            struct Bytes;
+
+            impl<'de> Visitor<'de> for Bytes {
+                type Value = Vec<u8>;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a buffer")
+                }
+
+                fn visit_bytes<E>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+                    Ok(v.to_vec())
+                }
+            }
+
+            d.deserialize_bytes(Bytes)
+        }
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let ctx = DukContext::new().unwrap();
+        let mut ids = BTreeMap::new();
+        ids.insert(7, (-1, 0.5));
+        let config = Config {
+            players: vec![
+                Player {
+                    name: "a".to_owned(),
+                    score: Some(3),
+                    role: Role::Admin,
+                },
+                Player {
+                    name: "b".to_owned(),
+                    score: None,
+                    role: Role::Member { level: 2 },
+                },
+                Player {
+                    name: "c".to_owned(),
+                    score: None,
+                    role: Role::Guest("x".to_owned()),
+                },
+            ],
+            ids,
+            data: vec![1, 2, 3],
+        };
+
+        to_value(&ctx, &config).unwrap();
+        ctx.push_global_object()
+            .dup(-2)
+            .put_prop_string(-2, "config")
+            .pop(1);
+        let json: String = ctx
+            .eval("JSON.stringify({ players: config.players, ids: config.ids, len: config.data.length })")
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert_eq!(
+            json,
+            r#"{"players":[{"name":"a","score":3,"role":"Admin"},{"name":"b","score":null,"role":{"Member":{"level":2}}},{"name":"c","score":null,"role":{"Guest":"x"}}],"ids":{"7":[-1,0.5]},"len":3}"#
+        );
+        ctx.pop(1);
+
+        let back: Config = from_value(&ctx, -1).unwrap();
+        assert_eq!(back, config);
+        assert_eq!(ctx.top(), 1);
+    }
+
+    #[test]
+    fn serde_error_paths() {
+        let ctx = DukContext::new().unwrap();
+        ctx.eval(
+            "({ players: [{ name: 'a', role: 'Admin' }, { name: 1, role: 'Admin' }], ids: {}, data: [] })",
+        )
+        .unwrap();
+        let err = from_value::<Config>(&ctx, -1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Type error: players[1].name: expected string, got number"
+        );
+        assert_eq!(ctx.top(), 1);
+    }
+}
@@ -1,16 +1,33 @@
-use super::error::{ErrorKind, Result};
+use super::error::{Error, ErrorKind, JsException, Result, ThrownValue};
+use super::heap::{self, Allocator, Heap, MemoryStats, System};
 use super::privates;
 use crate::callable::push_callable;
 use crate::callable::Callable;
 use crate::class::push_class_builder;
 use crate::class::Builder;
+use crate::debugger;
+use crate::handle::{self, ContextHandle};
+#[cfg(feature = "exec-timeout")]
+use crate::interrupt::{self, Interrupt, InterruptHandle};
+use crate::modules::{self, ModuleResolver, NativeModule};
+use crate::promise::{self, JsFuture};
+use crate::scope::Scope;
+use crate::sourcemap::{self, SourceMap};
+use crate::types::strip_bytecode_header;
 use crate::types::FromDuktape;
+use crate::types::Function;
+use crate::types::PersistentRef;
 use crate::types::ToDuktape;
 use crate::types::Type;
 use dukbind::*;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::fmt;
+use std::future::Future;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::ptr;
+use std::time::Duration;
+#[cfg(feature = "exec-timeout")]
+use std::time::Instant;
 use typemap::TypeMap;
 
 pub type Idx = i32;
@@ -50,6 +67,86 @@ bitflags! {
     }
 }
 
+/// Configures the heap of a new `DukContext`
+pub struct ContextBuilder {
+    allocator: Option<Box<dyn Allocator>>,
+    memory_limit: Option<usize>,
+    fatal_handler: Option<Box<dyn Fn(&str)>>,
+    check_stack: bool,
+}
+
+impl ContextBuilder {
+    /// Route every heap allocation through `allocator`
+    pub fn allocator<T: 'static + Allocator>(&mut self, allocator: T) -> &mut Self {
+        self.allocator = Some(Box::new(allocator));
+        self
+    }
+
+    /// Refuse allocations which would grow the heap above `bytes`.
+    /// Scripts see a `RangeError`, the host an `ErrorKind::InsufficientMemory`.
+    pub fn memory_limit(&mut self, bytes: usize) -> &mut Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Called with the message of an unrecoverable error, e.g. an error thrown
+    /// outside any protected call. The process is aborted once it returns,
+    /// as the heap cannot be used anymore.
+    pub fn fatal_handler<T: 'static + Fn(&str)>(&mut self, handler: T) -> &mut Self {
+        self.fatal_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Make native functions fail with `ErrorKind::UnbalancedStack` when they
+    /// leave anything but their return values above their arguments.
+    /// Meant for debug builds, e.g. `.check_stack(cfg!(debug_assertions))`.
+    pub fn check_stack(&mut self, enabled: bool) -> &mut Self {
+        self.check_stack = enabled;
+        self
+    }
+
+    pub fn build(&mut self) -> Result<DukContext> {
+        let allocator = self.allocator.take().unwrap_or_else(|| Box::new(System));
+        let heap = Heap::new(
+            allocator,
+            self.memory_limit,
+            self.fatal_handler.take(),
+            self.check_stack,
+        );
+
+        let d = unsafe { heap::create_heap(heap) };
+        if d.is_null() {
+            return Err(ErrorKind::InsufficientMemory.into());
+        }
+
+        unsafe { privates::init_refs(d) };
+        unsafe { privates::init_data(d) };
+
+        Ok(DukContext {
+            inner: d,
+            managed: true,
+            data: unsafe { privates::get_data(d) },
+        })
+    }
+}
+
+/// Normalizes any thrown value without letting it throw again
+static DESCRIBE_ERROR: &'static str = r#"(function (e) {
+    var info = { name: '', message: '' };
+    try {
+        if (e !== null && (typeof e === 'object' || typeof e === 'function')) {
+            if (typeof e.name === 'string') info.name = e.name;
+            info.message = typeof e.message === 'string' ? e.message : String(e);
+            if (typeof e.stack === 'string') info.stack = e.stack;
+            if (typeof e.fileName === 'string') info.fileName = e.fileName;
+            if (typeof e.lineNumber === 'number') info.lineNumber = e.lineNumber;
+        } else {
+            info.message = String(e);
+        }
+    } catch (x) {}
+    return info;
+})"#;
+
 #[derive(Clone)]
 pub struct DukContext {
     pub(crate) inner: *mut duk_context,
@@ -57,24 +154,21 @@ pub struct DukContext {
     data: *mut TypeMap,
 }
 
+/// A protected call started by `DukContext::enter_call`
+struct Call<'a>(Option<&'a Heap>);
+
+impl<'a> Drop for Call<'a> {
+    fn drop(&mut self) {
+        if let Some(heap) = self.0 {
+            heap.leave();
+        }
+    }
+}
+
 macro_rules! handle_error {
     ($ret: expr, $ctx: expr) => {
         if ($ret) != DUK_EXEC_SUCCESS as i32 {
-            if $ctx.has_prop_string(-1, "stack") {
-                $ctx.get_prop_string(-1, "stack");
-            } else {
-                $ctx.get_prop_string(-1, "message");
-            }
-
-            let msg: String;
-            if $ctx.is_string(-1) {
-                msg = $ctx.get_string(-1)?.to_owned();
-            } else {
-                msg = "Uknown".to_string();
-            }
-            $ctx.pop(2);
-
-            return Err(ErrorKind::TypeError(msg).into());
+            return Err($ctx.take_error());
         }
     };
 }
@@ -95,19 +189,17 @@ macro_rules! check_impl {
 impl DukContext {
     /// 创建js虚拟机实例
     pub fn new() -> Result<DukContext> {
-        let d = unsafe { duk_create_heap_default() };
-        if d.is_null() {
-            return Err(ErrorKind::InsufficientMemory.into());
-        }
-
-        unsafe { privates::init_refs(d) };
-        unsafe { privates::init_data(d) };
+        DukContext::builder().build()
+    }
 
-        Ok(DukContext {
-            inner: d,
-            managed: true,
-            data: unsafe { privates::get_data(d) },
-        })
+    /// Configure the heap of a new context
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder {
+            allocator: None,
+            memory_limit: None,
+            fatal_handler: None,
+            check_stack: false,
+        }
     }
 
     /// Create a new context, from a given duktape context
@@ -122,6 +214,229 @@ impl DukContext {
         }
     }
 
+    /// Run `f` with a `Scope` owning the values it pushes. The stack is
+    /// restored to its current top once `f` returns.
+    pub fn scope<R, F: FnOnce(&mut Scope) -> R>(&self, f: F) -> R {
+        f(&mut Scope::new(self))
+    }
+
+    /// Current and peak allocation of the heap
+    pub fn memory_stats(&self) -> Option<MemoryStats> {
+        unsafe { heap::get_heap(self.inner) }.map(|h| h.stats())
+    }
+
+    /// Abort scripts still running at `deadline` with `ErrorKind::Timeout`.
+    /// The deadline is cleared once it fired.
+    #[cfg(feature = "exec-timeout")]
+    pub fn set_deadline(&self, deadline: Option<Instant>) -> Result<&Self> {
+        if deadline.is_some() {
+            self.interrupt()?.set_deadline(deadline);
+        } else if let Some(heap) = unsafe { heap::get_heap(self.inner) } {
+            heap.interrupt.set_deadline(None);
+        }
+        Ok(self)
+    }
+
+    /// Abort scripts still running after `timeout` from now
+    #[cfg(feature = "exec-timeout")]
+    pub fn set_execution_timeout(&self, timeout: Option<Duration>) -> Result<&Self> {
+        self.set_deadline(timeout.map(|t| Instant::now() + t))
+    }
+
+    /// Abort scripts after `steps` timeout checks, each of which duktape
+    /// performs after executing a batch of bytecode instructions.
+    /// The budget is cleared once it is exhausted.
+    #[cfg(feature = "exec-timeout")]
+    pub fn set_step_budget(&self, steps: Option<u64>) -> Result<&Self> {
+        if steps.is_some() {
+            self.interrupt()?.set_budget(steps);
+        } else if let Some(heap) = unsafe { heap::get_heap(self.inner) } {
+            heap.interrupt.set_budget(None);
+        }
+        Ok(self)
+    }
+
+    /// Handle for aborting the running script from another thread
+    #[cfg(feature = "exec-timeout")]
+    pub fn interrupt_handle(&self) -> Result<InterruptHandle> {
+        Ok(self.interrupt()?.handle())
+    }
+
+    /// The timeout state of the heap, if the engine checks it at all
+    #[cfg(feature = "exec-timeout")]
+    fn interrupt(&self) -> Result<&Interrupt> {
+        let heap = match unsafe { heap::get_heap(self.inner) } {
+            Some(heap) => heap,
+            None => bail!(ErrorKind::Error(
+                "context has no heap to track timeouts in".to_owned()
+            )),
+        };
+        if !interrupt::supported(self) {
+            bail!(ErrorKind::Error(
+                "duktape is built without DUK_USE_EXEC_TIMEOUT_CHECK".to_owned()
+            ));
+        }
+        Ok(&heap.interrupt)
+    }
+
+    /// Start a protected call, left once the returned guard is dropped
+    fn enter_call(&self) -> Call<'_> {
+        let heap = unsafe { heap::get_heap(self.inner) };
+        if let Some(heap) = heap {
+            heap.enter();
+        }
+        Call(heap)
+    }
+
+    /// Pop the error thrown by a failed protected call, and convert it
+    pub(crate) fn take_error(&self) -> Error {
+        // Limits keep failing scripts until they are taken, so they are
+        // checked before running any
+        if let Some(kind) = self.heap_error(-1) {
+            self.pop(1);
+            return kind.into();
+        }
+
+        let exception = self.describe_error(-1);
+        self.pop(1);
+        exception.into_error()
+    }
+
+    /// Push the script value an error returned by Rust code is thrown as.
+    /// Script exceptions are rethrown as they were, other errors become an
+    /// instance of the matching error constructor, with the Rust error
+    /// chain in its `rustChain` property.
+    pub(crate) fn push_error(&self, err: Error) -> &Self {
+        let thrown = match *err.kind() {
+            ErrorKind::Throw(ref value) => Some(value),
+            _ => err.exception().and_then(|e| e.value.as_ref()),
+        };
+        // Values of another heap are thrown as a plain error instead
+        if thrown
+            .and_then(ThrownValue::get)
+            .map_or(false, |value| value.push(self).is_ok())
+        {
+            return self;
+        }
+
+        let (code, message) = match *err.kind() {
+            ErrorKind::TypeError(ref m) => (DUK_ERR_TYPE_ERROR, m.clone()),
+            ErrorKind::ReferenceError(ref m) => (DUK_ERR_REFERENCE_ERROR, m.clone()),
+            ErrorKind::EvalError(ref m) => (DUK_ERR_EVAL_ERROR, m.clone()),
+            ErrorKind::RangeError(ref m) => (DUK_ERR_RANGE_ERROR, m.clone()),
+            ErrorKind::SyntaxError(ref m) => (DUK_ERR_SYNTAX_ERROR, m.clone()),
+            ErrorKind::URIError(ref m) => (DUK_ERR_URI_ERROR, m.clone()),
+            ErrorKind::Error(ref m) => (DUK_ERR_ERROR, m.clone()),
+            // Raised again as the errors of the limits, the outer calls
+            // fail the same way
+            ErrorKind::Timeout => (DUK_ERR_RANGE_ERROR, err.kind().to_string()),
+            ErrorKind::InsufficientMemory => {
+                if let Some(heap) = unsafe { heap::get_heap(self.inner) } {
+                    heap.set_limit_hit();
+                }
+                (DUK_ERR_RANGE_ERROR, err.kind().to_string())
+            }
+            ref k => (DUK_ERR_ERROR, k.to_string()),
+        };
+        let message = CString::new(message.replace('\0', "")).unwrap();
+        unsafe {
+            duk_push_error_object_raw(
+                self.inner,
+                code as i32,
+                ptr::null(),
+                0,
+                b"%s\0".as_ptr() as *const i8,
+                message.as_ptr(),
+            );
+        }
+
+        self.push_array();
+        for (i, e) in err.iter().enumerate() {
+            self.push_string(e.to_string()).put_prop_index(-2, i as u32);
+        }
+        self.put_prop_string(-2, "rustChain");
+        self
+    }
+
+    /// Read the properties of a thrown value. This runs in a protected
+    /// call, since getters on the value may throw as well.
+    pub(crate) fn describe_error(&self, idx: Idx) -> JsException {
+        let idx = self.normalize_index(idx);
+        let mut exception = JsException {
+            name: String::new(),
+            message: "Unknown".to_owned(),
+            stack: None,
+            file_name: None,
+            line_number: None,
+            value: PersistentRef::new(self, idx).ok().map(ThrownValue::new),
+        };
+
+        let ret = unsafe {
+            privates::duk_peval_lstring(
+                self.inner,
+                DESCRIBE_ERROR.as_ptr() as *const i8,
+                DESCRIBE_ERROR.len(),
+            )
+        };
+        if ret != DUK_EXEC_SUCCESS as i32 {
+            self.pop(1);
+            return exception;
+        }
+        self.dup(idx);
+        if unsafe { duk_pcall(self.inner, 1) } != DUK_EXEC_SUCCESS as i32 {
+            self.pop(1);
+            return exception;
+        }
+
+        let string = |key: &str| {
+            self.get_prop_string(-1, key);
+            let ret = self.get_string(-1).ok().map(|s| s.to_owned());
+            self.pop(1);
+            ret
+        };
+        if let Some(name) = string("name") {
+            exception.name = name;
+        }
+        if let Some(message) = string("message") {
+            exception.message = message;
+        }
+        exception.stack = string("stack");
+        exception.file_name = string("fileName");
+        self.get_prop_string(-1, "lineNumber");
+        exception.line_number = self.get_number(-1).ok().map(|n| n as u32);
+        self.pop(2);
+
+        exception
+    }
+
+    /// Map errors raised by the heap limits onto their own error kinds.
+    /// Reads the error code natively, without running any script.
+    fn heap_error(&self, idx: Idx) -> Option<ErrorKind> {
+        let heap = match unsafe { heap::get_heap(self.inner) } {
+            Some(h) => h,
+            None => return None,
+        };
+        let range_error =
+            unsafe { duk_get_error_code(self.inner, idx) } == DUK_ERR_RANGE_ERROR as i32;
+
+        let memory = heap.take_limit_hit();
+        #[cfg(feature = "exec-timeout")]
+        {
+            if heap.interrupt.fired() {
+                // Keeps firing in nested calls, until the error reaches
+                // the host
+                if heap.is_outermost() {
+                    heap.interrupt.clear();
+                }
+                return Some(ErrorKind::Timeout);
+            }
+        }
+        if memory && range_error {
+            return Some(ErrorKind::InsufficientMemory);
+        }
+        None
+    }
+
     pub fn data<'a>(&'a self) -> Result<&'a TypeMap> {
         unsafe {
             if self.data.is_null() {
@@ -142,6 +457,7 @@ impl DukContext {
 
     /// Evaluate a script
     pub fn eval<T: AsRef<[u8]>>(&self, script: T) -> Result<&Self> {
+        let _call = self.enter_call();
         let script = script.as_ref();
 
         let ret = unsafe {
@@ -154,6 +470,7 @@ impl DukContext {
     }
 
     pub fn compile(&self, flags: Compile) -> Result<&Self> {
+        let _call = self.enter_call();
         let ret = unsafe { privates::duk_pcompile(self.inner, flags.bits()) };
         handle_error!(ret, self);
 
@@ -161,6 +478,7 @@ impl DukContext {
     }
 
     pub fn compile_string<T: AsRef<[u8]>>(&self, content: T, flags: Compile) -> Result<()> {
+        let _call = self.enter_call();
         let content = content.as_ref();
         let len = content.len();
 
@@ -183,6 +501,7 @@ impl DukContext {
         file_name: &str,
         flags: Compile,
     ) -> Result<()> {
+        let _call = self.enter_call();
         let content = content.as_ref();
         let len = content.len();
 
@@ -200,6 +519,28 @@ impl DukContext {
         Ok(())
     }
 
+    /// Load a function dumped with `Function::dump_bytecode`
+    ///
+    /// Fails with `ErrorKind::IncompatibleBytecode` if the bytecode was
+    /// produced by another Duktape version or platform. The bytecode itself
+    /// is not validated by Duktape, so only load bytecode you produced.
+    pub fn load_bytecode(&self, bytes: &[u8]) -> Result<Function> {
+        let _call = self.enter_call();
+        let bytecode = strip_bytecode_header(bytes)?;
+        self.push_bytes(bytecode);
+        let ret = unsafe {
+            privates::safe_call(self.inner, 1, 1, |ctx| {
+                duk_load_function(ctx);
+                1
+            })
+        };
+        handle_error!(ret, self);
+
+        let function = Function::from_context(self, -1)?;
+        self.pop(1);
+        Ok(function)
+    }
+
     pub fn dump(&self) -> String {
         unsafe {
             duk_push_context_dump(self.inner);
@@ -338,6 +679,24 @@ impl DukContext {
         Ok(ret)
     }
 
+    /// Convert the value at `idx` with ECMAScript ToInt32, which wraps
+    /// large numbers and calls `valueOf` of objects. The value itself is
+    /// left as is, errors thrown by the conversion are returned.
+    pub fn to_int32(&self, idx: Idx) -> Result<i32> {
+        let _call = self.enter_call();
+        let ret = unsafe {
+            duk_dup(self.inner, idx);
+            privates::safe_call(self.inner, 1, 1, |ctx| {
+                duk_to_int32(ctx, -1);
+                1
+            })
+        };
+        handle_error!(ret, self);
+        let ret = unsafe { duk_get_int(self.inner, -1) };
+        self.pop(1);
+        Ok(ret)
+    }
+
     pub fn get_uint(&self, idx: Idx) -> Result<u32> {
         if !self.is_number(idx) {
             bail!(ErrorKind::TypeError(format!("number")));
@@ -498,18 +857,39 @@ impl DukContext {
         self
     }
 
-    pub fn duk_put_prop(&self, index: i32) -> &Self {
-        unsafe {
-            duk_put_prop(self.inner, index);
-        }
-        self
+    /// Set the property of the object at `index`, with the key and value on top of the stack.
+    /// Errors thrown by setters or proxies are returned instead of propagated.
+    pub fn duk_put_prop(&self, index: i32) -> Result<&Self> {
+        let _call = self.enter_call();
+        let index = self.normalize_index(index);
+        let ret = unsafe {
+            duk_dup(self.inner, index);
+            duk_insert(self.inner, -3);
+            privates::safe_call(self.inner, 3, 1, |ctx| {
+                duk_put_prop(ctx, -3);
+                0
+            })
+        };
+        handle_error!(ret, self);
+        self.pop(1);
+        Ok(self)
     }
 
-    pub fn duk_get_prop(&self, index: i32) -> &Self {
-        unsafe {
-            duk_get_prop(self.inner, index);
-        }
-        self
+    /// Replace the key on top of the stack with the property of the object at `index`.
+    /// Errors thrown by getters or proxies are returned instead of propagated.
+    pub fn duk_get_prop(&self, index: i32) -> Result<&Self> {
+        let _call = self.enter_call();
+        let index = self.normalize_index(index);
+        let ret = unsafe {
+            duk_dup(self.inner, index);
+            duk_insert(self.inner, -2);
+            privates::safe_call(self.inner, 2, 1, |ctx| {
+                duk_get_prop(ctx, -2);
+                1
+            })
+        };
+        handle_error!(ret, self);
+        Ok(self)
     }
 
     pub fn get_prop_index(&self, aidx: Idx, index: u32) -> &Self {
@@ -581,32 +961,43 @@ impl DukContext {
 
     // Strings
     pub fn concat(&self, argc: i32) -> Result<()> {
+        let _call = self.enter_call();
         if argc > self.top() {
             return Err(ErrorKind::ReferenceError(format!("invalid index: {}", argc)).into());
         }
-        unsafe { duk_concat(self.inner, argc) };
+        let ret = unsafe {
+            privates::safe_call(self.inner, argc, 1, |ctx| {
+                duk_concat(ctx, argc);
+                1
+            })
+        };
+        handle_error!(ret, self);
         Ok(())
     }
 
     pub fn call(&self, args: i32) -> Result<&Self> {
+        let _call = self.enter_call();
         let ret = unsafe { duk_pcall(self.inner, args) };
         handle_error!(ret, self);
         Ok(self)
     }
 
     pub fn call_method(&self, args: i32) -> Result<&Self> {
+        let _call = self.enter_call();
         let ret = unsafe { duk_pcall_method(self.inner, args) };
         handle_error!(ret, self);
         Ok(self)
     }
 
     pub fn call_prop(&self, idx: Idx, args: i32) -> Result<&Self> {
+        let _call = self.enter_call();
         let ret = unsafe { duk_pcall_prop(self.inner, idx, args) };
         handle_error!(ret, self);
         Ok(self)
     }
 
     pub fn construct(&self, args: i32) -> Result<&Self> {
+        let _call = self.enter_call();
         let ret = unsafe { duk_pnew(self.inner, args) };
         handle_error!(ret, self);
         Ok(self)
@@ -633,6 +1024,107 @@ impl DukContext {
         }
     }
 
+    // Modules
+    /// Register a native module, making it available to `require()`
+    pub fn register_module<T: 'static + NativeModule>(&self, module: T) -> Result<&Self> {
+        modules::register_module(self, Box::new(module))?;
+        Ok(self)
+    }
+
+    /// Set the resolver used by `require()` for non-native modules
+    pub fn set_module_resolver<T: 'static + ModuleResolver>(&self, resolver: T) -> Result<&Self> {
+        modules::set_resolver(self, Box::new(resolver))?;
+        Ok(self)
+    }
+
+    /// Handle for posting jobs to this context from other threads
+    pub fn handle(&self) -> Result<ContextHandle> {
+        handle::handle(self)
+    }
+
+    /// Run the jobs posted through `ContextHandle`s, returning how many ran
+    pub fn run_jobs(&self) -> Result<usize> {
+        handle::run_jobs(self)
+    }
+
+    /// Block until a job is posted or `timeout` elapsed, then run the
+    /// queued jobs, returning how many ran
+    pub fn wait_jobs(&self, timeout: Option<Duration>) -> Result<usize> {
+        handle::wait_jobs(self, timeout)
+    }
+
+    /// Install the `Promise` polyfill, unless the engine provides `Promise`
+    pub fn init_promise(&self) -> Result<&Self> {
+        promise::init(self)?;
+        Ok(self)
+    }
+
+    /// Run the reactions of settled promises, returning how many ran
+    pub fn run_microtasks(&self) -> Result<usize> {
+        promise::run_microtasks(self)
+    }
+
+    /// Number of futures returned to scripts which did not complete yet
+    pub fn pending_futures(&self) -> usize {
+        promise::pending_futures(self)
+    }
+
+    /// Await the promise, thenable or plain value at `idx`
+    pub fn to_future(&self, idx: Idx) -> Result<JsFuture> {
+        promise::to_future(self, idx)
+    }
+
+    /// Poll `future` to completion on this thread, running posted jobs and
+    /// promise reactions while it is pending. Timers are not run, promises
+    /// settled by them need the event loop of the host, e.g. the
+    /// `EventLoop::block_on` of `js_binding`.
+    pub fn block_on<F: Future>(&self, future: F) -> Result<F::Output> {
+        promise::block_on(self, future)
+    }
+
+    /// Listen for debug clients on `addr`, returning the bound address.
+    /// Clients are accepted by `cooperate_debugger` or `wait_debugger`.
+    pub fn listen_debugger<A: ToSocketAddrs>(&self, addr: A) -> Result<SocketAddr> {
+        debugger::listen(self, addr)
+    }
+
+    /// Accept a pending debug client, or process the pending messages of
+    /// the attached one. Must not be called while a script is running.
+    pub fn cooperate_debugger(&self) -> Result<&Self> {
+        debugger::cooperate(self)?;
+        Ok(self)
+    }
+
+    /// Block until a debug client attaches
+    pub fn wait_debugger(&self) -> Result<&Self> {
+        debugger::wait(self)?;
+        Ok(self)
+    }
+
+    pub fn is_debugger_attached(&self) -> bool {
+        debugger::is_attached(self)
+    }
+
+    /// Detach the debug client, if any. The socket keeps listening.
+    pub fn detach_debugger(&self) -> &Self {
+        debugger::detach(self);
+        self
+    }
+
+    /// Map the errors of scripts compiled with `file_name` through a source map.
+    /// `map` is the JSON of a version 3 source map.
+    pub fn register_source_map<T: AsRef<str>>(&self, file_name: &str, map: T) -> Result<&Self> {
+        let map = SourceMap::parse(self, map)?;
+        sourcemap::register(self, file_name, map)?;
+        Ok(self)
+    }
+
+    /// Require a module from the global scope and push its exports onto the stack
+    pub fn require(&self, id: &str) -> Result<&Self> {
+        modules::require(self, id, "")?;
+        Ok(self)
+    }
+
     pub fn create<'a, T: Constructable<'a>>(&'a self) -> Result<T> {
         T::construct(self)
     }
@@ -653,7 +1145,15 @@ impl DukContext {
     }
 
     pub fn enumerator(&self, index: Idx, flags: Enumerate) -> Result<()> {
-        unsafe { duk_enum(self.inner, index, flags.bits()) };
+        let _call = self.enter_call();
+        let ret = unsafe {
+            duk_dup(self.inner, index);
+            privates::safe_call(self.inner, 1, 1, |ctx| {
+                duk_enum(ctx, -1, flags.bits());
+                1
+            })
+        };
+        handle_error!(ret, self);
         Ok(())
     }
 
@@ -667,11 +1167,9 @@ impl DukContext {
         Ok(out)
     }
 
-    pub fn duk_eval_string(&self, code: &str) -> &Self {
-        unsafe {
-            duk_eval_string(self.inner, code);
-        }
-        self
+    /// Evaluate a script, alias of `eval`
+    pub fn duk_eval_string(&self, code: &str) -> Result<&Self> {
+        self.eval(code)
     }
 }
 
@@ -679,7 +1177,7 @@ impl Drop for DukContext {
     fn drop(&mut self) {
         if !self.inner.is_null() && self.managed {
             unsafe {
-                duk_destroy_heap(self.inner);
+                heap::destroy_heap(self.inner);
             };
         }
 
@@ -707,10 +1205,210 @@ impl std::cmp::PartialEq for DukContext {
 
 #[cfg(test)]
 mod test {
-    use super::DukContext;
+    use super::{Compile, DukContext, Enumerate};
+    use crate::error::{Error, ErrorKind};
+    use crate::types::Function;
+    use std::cell::RefCell;
 
     #[test]
     fn ctx_new() {
         let _ctx = DukContext::new();
     }
+
+    #[test]
+    fn protected_properties() {
+        let ctx = DukContext::new().unwrap();
+        ctx.eval(
+            "({ get boom() { throw new Error('get'); }, set boom(v) { throw new Error('set'); } })",
+        )
+        .unwrap();
+
+        ctx.push_string("boom");
+        assert!(ctx.duk_get_prop(-2).is_err());
+        ctx.push_string("boom").push_int(1);
+        assert!(ctx.duk_put_prop(-3).is_err());
+        assert_eq!(ctx.top(), 1);
+
+        ctx.push_string("other").push_int(1);
+        ctx.duk_put_prop(-3).unwrap();
+        ctx.push_string("other");
+        assert_eq!(ctx.duk_get_prop(-2).unwrap().getp::<i32>().unwrap(), 1);
+        assert_eq!(ctx.top(), 1);
+    }
+
+    #[test]
+    fn protected_entry_points() {
+        let ctx = DukContext::new().unwrap();
+        ctx.push_string("a");
+        ctx.eval("({ toString: function () { throw new Error('boom'); } })")
+            .unwrap();
+        assert!(ctx.concat(2).is_err());
+        assert_eq!(ctx.top(), 0);
+
+        ctx.push_undefined();
+        assert!(ctx.enumerator(-1, Enumerate::OWN_PROPERTIES_ONLY).is_err());
+        assert_eq!(ctx.top(), 1);
+
+        assert!(ctx.duk_eval_string("throw new Error('boom')").is_err());
+        assert_eq!(ctx.top(), 1);
+    }
+
+    #[test]
+    fn js_exceptions() {
+        let ctx = DukContext::new().unwrap();
+
+        let err = ctx
+            .compile_string_filename("var a = ;", "broken.js", super::Compile::EVAL)
+            .unwrap_err();
+        let exception = err.exception().unwrap();
+        assert_eq!(exception.name, "SyntaxError");
+        match err.kind() {
+            ErrorKind::SyntaxError(_) => {}
+            k => panic!("unexpected error: {}", k),
+        }
+
+        ctx.compile_string_filename("\nnull.x;", "null.js", super::Compile::EVAL)
+            .unwrap();
+        let err = ctx.call(0).unwrap_err();
+        match err.kind() {
+            ErrorKind::TypeError(_) => {}
+            k => panic!("unexpected error: {}", k),
+        }
+        let exception = err.exception().unwrap();
+        assert_eq!(exception.name, "TypeError");
+        assert_eq!(exception.file_name.as_ref().unwrap(), "null.js");
+        assert_eq!(exception.line_number, Some(2));
+        assert!(exception.stack.is_some());
+
+        let err = ctx.eval("undefinedVariable").unwrap_err();
+        match err.kind() {
+            ErrorKind::ReferenceError(_) => {}
+            k => panic!("unexpected error: {}", k),
+        }
+
+        let err = ctx.eval("throw { code: 42 }").unwrap_err();
+        let exception = err.exception().unwrap();
+        assert_eq!(exception.name, "");
+        let code: i32 = exception
+            .value(&ctx)
+            .unwrap()
+            .get::<crate::types::Object>()
+            .unwrap()
+            .get("code")
+            .unwrap();
+        assert_eq!(code, 42);
+
+        // Each exception keeps its own value
+        let err2 = ctx.eval("throw 'again'").unwrap_err();
+        assert_eq!(err2.exception().unwrap().message, "again");
+        assert!(exception.value(&ctx).is_some());
+
+        // and is rethrown as that value by Rust functions
+        let stored = RefCell::new(Some(err));
+        ctx.push_global_object()
+            .push_function(move |_: &DukContext| -> crate::error::Result<i32> {
+                Err(stored.borrow_mut().take().unwrap())
+            })
+            .put_prop_string(-2, "rethrow")
+            .pop(1);
+        let code: i32 = ctx
+            .eval("try { rethrow(); } catch (e) { e.code }")
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert_eq!(code, 42);
+        assert_eq!(ctx.top(), 0);
+    }
+
+    #[test]
+    fn typed_errors_from_callables() {
+        let ctx = DukContext::new().unwrap();
+        ctx.push_global_object()
+            .push_function(|_: &DukContext| -> crate::error::Result<i32> {
+                Err(Error::range_err("out of range"))
+            })
+            .put_prop_string(-2, "range")
+            .push_function(|ctx: &DukContext| -> crate::error::Result<i32> {
+                Err(Error::throw(ctx, "plain"))
+            })
+            .push_function(|ctx: &DukContext| -> crate::error::Result<i32> {
+                ctx.eval("throw { code: 7 }")?;
+                Ok(0)
+            })
+            .put_prop_string(-3, "rethrow")
+            .put_prop_string(-2, "plain")
+            .pop(1);
+
+        let ok: bool = ctx
+            .eval(
+                r#"
+                var ok = true;
+                try { range(); ok = false; } catch (e) {
+                    ok = ok && e instanceof RangeError && e.message === 'out of range';
+                    ok = ok && e.rustChain[0] === 'Range error: out of range';
+                }
+                try { plain(); ok = false; } catch (e) { ok = ok && e === 'plain'; }
+                try { rethrow(); ok = false; } catch (e) { ok = ok && e.code === 7; }
+                ok
+                "#,
+            )
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert!(ok);
+
+        match ctx.eval("range()").unwrap_err().kind() {
+            ErrorKind::RangeError(m) => assert_eq!(m, "out of range"),
+            k => panic!("unexpected error: {}", k),
+        }
+    }
+
+    #[test]
+    fn dropped_thrown_values() {
+        let ctx = DukContext::new().unwrap();
+        ctx.eval(
+            "var finalized = false; \
+             var o = {}; Duktape.fin(o, function () { finalized = true; }); o",
+        )
+        .unwrap();
+        let err = Error::throw(&ctx, crate::types::Ref::new(&ctx, -1));
+        ctx.pop(1);
+
+        let finalized = "o = null; Duktape.gc(); finalized";
+        assert!(!ctx.eval(finalized).unwrap().getp::<bool>().unwrap());
+        // Never thrown, the value is released along with the error
+        drop(err);
+        assert!(ctx.eval(finalized).unwrap().getp::<bool>().unwrap());
+    }
+
+    #[test]
+    fn bytecode_round_trip() {
+        let ctx = DukContext::new().unwrap();
+        ctx.compile_string("(function (a, b) { return a * b + 1; })", Compile::EVAL)
+            .unwrap();
+        ctx.call(0).unwrap();
+        let bytecode = ctx.getp::<Function>().unwrap().dump_bytecode().unwrap();
+
+        let other = DukContext::new().unwrap();
+        let function = other.load_bytecode(&bytecode).unwrap();
+        assert_eq!(function.call::<_, i32>((6, 7)).unwrap(), 43);
+        assert_eq!(other.top(), 0);
+
+        let mut foreign = bytecode.clone();
+        foreign[4] = foreign[4].wrapping_add(1);
+        for bad in &[&foreign[..], &bytecode[10..], &[][..]] {
+            match other.load_bytecode(bad) {
+                Err(e) => match e.kind() {
+                    ErrorKind::IncompatibleBytecode(_) => {}
+                    k => panic!("unexpected error: {}", k),
+                },
+                Ok(_) => panic!("bytecode should be rejected"),
+            }
+        }
+
+        // Native functions have no bytecode
+        other.push_function((0, |_: &DukContext| Ok(0)));
+        assert!(other.getp::<Function>().unwrap().dump_bytecode().is_err());
+        assert_eq!(other.top(), 0);
+    }
 }
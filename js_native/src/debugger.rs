@@ -0,0 +1,345 @@
+//!
+//! Duktape debug protocol over TCP
+//!
+//! `DukContext::listen_debugger` binds a local socket for debug clients,
+//! e.g. the VS Code Duktape debug adapter, to attach to. Clients are accepted
+//! and debug messages processed by `DukContext::cooperate_debugger`, which
+//! the host should call regularly while no script is running, e.g. once
+//! per frame. While paused, Duktape blocks inside the script and handles
+//! messages itself.
+//!
+//! The engine must be configured with `DUK_USE_DEBUGGER_SUPPORT` and
+//! `DUK_USE_INTERRUPT_COUNTER` for attaching to succeed.
+//!
+
+use super::ctx::DukContext;
+use super::error::{ErrorKind, Result};
+use super::privates;
+use dukbind::*;
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::io::{self, BufWriter, ErrorKind as IoErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::raw::c_char;
+use std::rc::Rc;
+use std::slice;
+use typemap::Key;
+
+/// Listening socket of a context and whether a client is attached
+struct Listener {
+    socket: TcpListener,
+    attached: Rc<Cell<bool>>,
+}
+
+struct Debugger;
+
+impl Key for Debugger {
+    type Value = Listener;
+}
+
+/// Connection to a client, owned by duktape between attach and detach
+struct Transport {
+    reader: TcpStream,
+    writer: BufWriter<TcpStream>,
+    attached: Rc<Cell<bool>>,
+}
+
+pub(crate) fn listen<A: ToSocketAddrs>(ctx: &DukContext, addr: A) -> Result<SocketAddr> {
+    if ctx.data()?.contains::<Debugger>() {
+        bail!(ErrorKind::Error("debugger is already listening".to_owned()));
+    }
+
+    let socket = TcpListener::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    let local = socket.local_addr()?;
+    ctx.data_mut()?.insert::<Debugger>(Listener {
+        socket,
+        attached: Rc::new(Cell::new(false)),
+    });
+    Ok(local)
+}
+
+/// Accept a pending client, or process the messages of the attached one
+pub(crate) fn cooperate(ctx: &DukContext) -> Result<()> {
+    let pending = match ctx.data()?.get::<Debugger>() {
+        Some(listener) if listener.attached.get() => None,
+        Some(listener) => match listener.socket.accept() {
+            Ok((stream, _)) => Some((stream, listener.attached.clone())),
+            Err(ref e) if e.kind() == IoErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e.into()),
+        },
+        None => return Ok(()),
+    };
+
+    match pending {
+        Some((stream, attached)) => attach(ctx, stream, attached),
+        None => {
+            unsafe { duk_debugger_cooperate(ctx.inner) };
+            Ok(())
+        }
+    }
+}
+
+/// Block until a client attaches, e.g. to debug scripts run on startup
+pub(crate) fn wait(ctx: &DukContext) -> Result<()> {
+    let (stream, attached) = match ctx.data()?.get::<Debugger>() {
+        Some(listener) if listener.attached.get() => return Ok(()),
+        Some(listener) => {
+            listener.socket.set_nonblocking(false)?;
+            let accepted = listener.socket.accept();
+            listener.socket.set_nonblocking(true)?;
+            (accepted?.0, listener.attached.clone())
+        }
+        None => bail!(ErrorKind::Error("debugger is not listening".to_owned())),
+    };
+
+    attach(ctx, stream, attached)
+}
+
+pub(crate) fn is_attached(ctx: &DukContext) -> bool {
+    match ctx.data() {
+        Ok(data) => data.get::<Debugger>().map_or(false, |l| l.attached.get()),
+        Err(_) => false,
+    }
+}
+
+pub(crate) fn detach(ctx: &DukContext) {
+    if is_attached(ctx) {
+        unsafe { duk_debugger_detach(ctx.inner) };
+    }
+}
+
+fn attach(ctx: &DukContext, stream: TcpStream, attached: Rc<Cell<bool>>) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    let transport = Box::into_raw(Box::new(Transport {
+        reader: stream.try_clone()?,
+        writer: BufWriter::new(stream),
+        attached: attached.clone(),
+    }));
+
+    // Marked first, the detached callback may run before attach returns
+    attached.set(true);
+    let ret = unsafe {
+        privates::safe_call(ctx.inner, 0, 1, |ctx| {
+            duk_debugger_attach(
+                ctx,
+                Some(debug_read),
+                Some(debug_write),
+                Some(debug_peek),
+                None,
+                Some(debug_write_flush),
+                None,
+                Some(debug_detached),
+                transport as *mut c_void,
+            );
+            0
+        })
+    };
+    if ret != DUK_EXEC_SUCCESS as i32 {
+        // Attaching failed before duktape took ownership of the transport
+        attached.set(false);
+        drop(unsafe { Box::from_raw(transport) });
+        return Err(ctx.take_error());
+    }
+
+    ctx.pop(1);
+    Ok(())
+}
+
+/// Block until at least one byte is read. Returning 0 detaches the client.
+unsafe extern "C" fn debug_read(
+    udata: *mut c_void,
+    buffer: *mut c_char,
+    length: duk_size_t,
+) -> duk_size_t {
+    let transport = &mut *(udata as *mut Transport);
+    let buffer = slice::from_raw_parts_mut(buffer as *mut u8, length);
+    loop {
+        match transport.reader.read(buffer) {
+            Err(ref e) if e.kind() == IoErrorKind::Interrupted => continue,
+            Ok(n) => return n,
+            Err(_) => return 0,
+        }
+    }
+}
+
+unsafe extern "C" fn debug_write(
+    udata: *mut c_void,
+    buffer: *const c_char,
+    length: duk_size_t,
+) -> duk_size_t {
+    let transport = &mut *(udata as *mut Transport);
+    let buffer = slice::from_raw_parts(buffer as *const u8, length);
+    match transport.writer.write_all(buffer) {
+        Ok(()) => length,
+        Err(_) => 0,
+    }
+}
+
+/// Number of bytes readable without blocking. A closed connection reports
+/// one byte, so the next read fails and duktape detaches.
+unsafe extern "C" fn debug_peek(udata: *mut c_void) -> duk_size_t {
+    let transport = &mut *(udata as *mut Transport);
+    let peek = |stream: &TcpStream| -> io::Result<usize> {
+        stream.set_nonblocking(true)?;
+        let ret = stream.peek(&mut [0; 1]);
+        stream.set_nonblocking(false)?;
+        ret
+    };
+    match peek(&transport.reader) {
+        Ok(0) => 1,
+        Ok(n) => n,
+        Err(ref e) if e.kind() == IoErrorKind::WouldBlock => 0,
+        Err(_) => 1,
+    }
+}
+
+unsafe extern "C" fn debug_write_flush(udata: *mut c_void) {
+    let transport = &mut *(udata as *mut Transport);
+    let _ = transport.writer.flush();
+}
+
+unsafe extern "C" fn debug_detached(_ctx: *mut duk_context, udata: *mut c_void) {
+    let transport = Box::from_raw(udata as *mut Transport);
+    transport.attached.set(false);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ptr;
+    use std::thread;
+    use std::time::Duration;
+
+    /// A connected client, and the transport of the accepted connection
+    fn connect() -> (TcpStream, *mut c_void, Rc<Cell<bool>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = listener.accept().unwrap().0;
+        let attached = Rc::new(Cell::new(true));
+        let transport = Box::new(Transport {
+            reader: stream.try_clone().unwrap(),
+            writer: BufWriter::new(stream),
+            attached: attached.clone(),
+        });
+        (client, Box::into_raw(transport) as *mut c_void, attached)
+    }
+
+    /// Peek until something is readable, data may still be in flight
+    unsafe fn readable(udata: *mut c_void) -> duk_size_t {
+        for _ in 0..500 {
+            let n = debug_peek(udata);
+            if n > 0 {
+                return n;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        0
+    }
+
+    #[test]
+    fn transport_callbacks() {
+        let (mut client, udata, attached) = connect();
+        let mut buffer = [0u8; 8];
+        unsafe {
+            let message = b"hello";
+            let written = debug_write(udata, message.as_ptr() as *const c_char, message.len());
+            assert_eq!(written, message.len());
+            debug_write_flush(udata);
+            let mut received = [0u8; 5];
+            client.read_exact(&mut received).unwrap();
+            assert_eq!(&received, message);
+
+            assert_eq!(debug_peek(udata), 0);
+            client.write_all(b"abc").unwrap();
+            assert_eq!(readable(udata), 1);
+            let mut read = Vec::new();
+            while read.len() < 3 {
+                let n = debug_read(udata, buffer.as_mut_ptr() as *mut c_char, buffer.len());
+                assert!(n > 0);
+                read.extend_from_slice(&buffer[..n]);
+            }
+            assert_eq!(read, b"abc");
+            assert_eq!(debug_peek(udata), 0);
+
+            // A closed connection stays readable, reading it detaches
+            drop(client);
+            assert_eq!(readable(udata), 1);
+            let n = debug_read(udata, buffer.as_mut_ptr() as *mut c_char, buffer.len());
+            assert_eq!(n, 0);
+
+            debug_detached(ptr::null_mut(), udata);
+        }
+        assert!(!attached.get());
+    }
+
+    #[test]
+    fn cooperate_without_clients() {
+        let ctx = DukContext::new().unwrap();
+        ctx.cooperate_debugger().unwrap();
+        assert!(ctx.wait_debugger().is_err());
+
+        ctx.listen_debugger("127.0.0.1:0").unwrap();
+        assert!(ctx.listen_debugger("127.0.0.1:0").is_err());
+        // Returns right away while nobody connects
+        ctx.cooperate_debugger().unwrap();
+        assert!(!ctx.is_debugger_attached());
+        assert_eq!(ctx.top(), 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "debugger"))]
+    fn attach_unsupported() {
+        let ctx = DukContext::new().unwrap();
+        let addr = ctx.listen_debugger("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(addr).unwrap();
+
+        // The engine refuses clients without `DUK_USE_DEBUGGER_SUPPORT`
+        let mut refused = false;
+        for _ in 0..500 {
+            if ctx.cooperate_debugger().is_err() {
+                refused = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(refused);
+        assert!(!ctx.is_debugger_attached());
+        assert_eq!(ctx.top(), 0);
+        ctx.eval("1 + 1").unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "debugger")]
+    fn attach_and_detach() {
+        use std::io::{BufRead, BufReader};
+
+        let ctx = DukContext::new().unwrap();
+        let addr = ctx.listen_debugger("127.0.0.1:0").unwrap();
+        assert!(ctx.listen_debugger("127.0.0.1:0").is_err());
+        assert!(!ctx.is_debugger_attached());
+
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            line
+        });
+
+        ctx.wait_debugger().unwrap();
+        assert!(ctx.is_debugger_attached());
+
+        // Duktape greets clients with the protocol and engine versions
+        let greeting = client.join().unwrap();
+        assert!(greeting.starts_with("2 "), "{}", greeting);
+
+        // The client hung up, which the next cooperation notices
+        ctx.cooperate_debugger().unwrap();
+        ctx.eval("1 + 1").unwrap();
+        ctx.cooperate_debugger().unwrap();
+        ctx.detach_debugger();
+        assert!(!ctx.is_debugger_attached());
+        assert_eq!(ctx.top(), 1);
+    }
+}
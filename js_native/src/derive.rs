@@ -0,0 +1,345 @@
+//!
+//! Support code for the expansions of `#[derive(ToDuktape, FromDuktape)]`
+//!
+
+use crate::ctx::{DukContext, Enumerate, Idx};
+use crate::error::{Error, ErrorKind, Result};
+use crate::types::{FromDuktape, Type};
+use std::error;
+use std::fmt;
+
+/// Location of a conversion error, chained to its `TypeError` so that
+/// enclosing values can extend it. Other errors, e.g. exceptions thrown by
+/// getters or the heap limits, are passed through as they are.
+#[derive(Debug)]
+struct Path {
+    /// Type the conversion started from
+    root: Option<String>,
+    /// Outermost first, either keys or `[index]`
+    segments: Vec<String>,
+    message: String,
+}
+
+impl Path {
+    /// The path of a conversion error, or any other error back
+    fn of(mut e: Error) -> ::std::result::Result<Path, Error> {
+        if let Some(next) = e.1.next_error.take() {
+            match next.downcast::<Path>() {
+                Ok(path) => return Ok(*path),
+                Err(next) => e.1.next_error = Some(next),
+            }
+        }
+        match *e.kind() {
+            ErrorKind::TypeError(ref message) if e.1.next_error.is_none() => Ok(Path {
+                root: None,
+                segments: Vec::new(),
+                message: message.clone(),
+            }),
+            _ => Err(e),
+        }
+    }
+
+    fn into_error(self) -> Error {
+        let message = self.to_string();
+        Error::with_chain(self, ErrorKind::TypeError(message))
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut path = self.root.clone().unwrap_or_default();
+        for segment in &self.segments {
+            if !path.is_empty() && !segment.starts_with('[') {
+                path.push('.');
+            }
+            path.push_str(segment);
+        }
+        if path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", path, self.message)
+        }
+    }
+}
+
+impl error::Error for Path {}
+
+/// Prefix a conversion error with the key or `[index]` of the value
+pub fn context(e: Error, segment: &str) -> Error {
+    match Path::of(e) {
+        Ok(mut path) => {
+            // Nested values are named by their location instead
+            path.root = None;
+            path.segments.insert(0, segment.to_owned());
+            path.into_error()
+        }
+        Err(e) => e,
+    }
+}
+
+/// Name a conversion error after the type being converted
+pub fn root(e: Error, name: &str) -> Error {
+    match Path::of(e) {
+        Ok(mut path) => {
+            path.root = Some(name.to_owned());
+            path.into_error()
+        }
+        Err(e) => e,
+    }
+}
+
+pub fn expect(ctx: &DukContext, idx: Idx, ty: Type) -> Result<()> {
+    let actual = ctx.get_type(idx);
+    if actual != ty {
+        bail!(ErrorKind::TypeError(format!(
+            "expected {:?}, got: {:?}",
+            ty, actual
+        )));
+    }
+    Ok(())
+}
+
+/// Convert the property `key` of the object at `idx`
+pub fn field<T>(ctx: &DukContext, idx: Idx, key: &str) -> Result<T>
+where
+    T: for<'de> FromDuktape<'de>,
+{
+    ctx.get_prop_string(idx, key);
+    let ret = T::from_context(ctx, -1);
+    ctx.pop(1);
+    ret.map_err(|e| context(e, key))
+}
+
+/// Like `field`, with missing properties defaulted
+pub fn field_or_default<T>(ctx: &DukContext, idx: Idx, key: &str) -> Result<T>
+where
+    T: Default + for<'de> FromDuktape<'de>,
+{
+    if !ctx.has_prop_string(idx, key) {
+        return Ok(T::default());
+    }
+    ctx.get_prop_string(idx, key);
+    let ret = if ctx.is_undefined(-1) {
+        Ok(T::default())
+    } else {
+        T::from_context(ctx, -1)
+    };
+    ctx.pop(1);
+    ret.map_err(|e| context(e, key))
+}
+
+/// Convert the element `index` of the array at `idx`
+pub fn element<T>(ctx: &DukContext, idx: Idx, index: u32) -> Result<T>
+where
+    T: for<'de> FromDuktape<'de>,
+{
+    ctx.get_prop_index(idx, index);
+    let ret = T::from_context(ctx, -1);
+    ctx.pop(1);
+    ret.map_err(|e| context(e, &format!("[{}]", index)))
+}
+
+/// Pop the object on top of the stack, copying its own properties into
+/// the object at `target`
+pub fn merge(ctx: &DukContext, target: Idx) -> Result<()> {
+    let target = ctx.normalize_index(target);
+    ctx.enumerator(-1, Enumerate::OWN_PROPERTIES_ONLY)?;
+    while ctx.next(-1, true)? {
+        ctx.duk_put_prop(target)?;
+    }
+    ctx.pop(2);
+    Ok(())
+}
+
+/// The variant name of an externally tagged enum at `idx`, either a string
+/// or an object with a single property
+pub fn variant(ctx: &DukContext, idx: Idx) -> Result<String> {
+    if ctx.is_string(idx) {
+        return Ok(ctx.get_string(idx)?.to_owned());
+    }
+    expect(ctx, idx, Type::Object)?;
+
+    let mut keys = Vec::new();
+    ctx.enumerator(idx, Enumerate::OWN_PROPERTIES_ONLY)?;
+    while ctx.next(-1, false)? {
+        keys.push(ctx.get_string(-1)?.to_owned());
+        ctx.pop(1);
+    }
+    ctx.pop(1);
+
+    if keys.len() != 1 {
+        bail!(ErrorKind::TypeError(format!(
+            "expected a single variant key, got {}",
+            keys.len()
+        )));
+    }
+    Ok(keys.remove(0))
+}
+
+pub fn unknown_variant(variant: &str) -> Error {
+    ErrorKind::TypeError(format!("unknown variant `{}`", variant)).into()
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod test {
+    use crate::ctx::{DukContext, Idx};
+    use crate::error::{ErrorKind, Result};
+    use crate::{FromDuktape, ToDuktape};
+    use std::collections::HashMap;
+
+    #[derive(ToDuktape, FromDuktape, Debug, PartialEq, Default)]
+    struct Meta {
+        version: u32,
+    }
+
+    #[derive(ToDuktape, FromDuktape, Debug, PartialEq)]
+    enum Shape {
+        Empty,
+        #[js(rename = "circle")]
+        Circle(f64),
+        Rect(f64, f64),
+        Poly {
+            points: Vec<f64>,
+        },
+    }
+
+    #[derive(ToDuktape, FromDuktape, Debug, PartialEq)]
+    struct Pair(String, i32);
+
+    #[derive(ToDuktape, FromDuktape, Debug, PartialEq)]
+    struct Wrap<T>(T);
+
+    /// Fails like a conversion hitting the memory limit
+    #[derive(Debug)]
+    struct Limited;
+
+    impl<'de> crate::types::FromDuktape<'de> for Limited {
+        fn from_context(_: &'de DukContext, _: Idx) -> Result<Self> {
+            Err(ErrorKind::InsufficientMemory.into())
+        }
+    }
+
+    /// Fails like a getter throwing
+    #[derive(Debug)]
+    struct Throwing;
+
+    impl<'de> crate::types::FromDuktape<'de> for Throwing {
+        fn from_context(ctx: &'de DukContext, _: Idx) -> Result<Self> {
+            ctx.eval("throw new TypeError('getter')").map(|_| Throwing)
+        }
+    }
+
+    #[derive(ToDuktape, FromDuktape, Debug, PartialEq)]
+    struct Config {
+        #[js(rename = "displayName")]
+        name: String,
+        shapes: Vec<Shape>,
+        pair: Pair,
+        tags: HashMap<String, bool>,
+        #[js(default)]
+        scale: Option<f64>,
+        #[js(skip)]
+        cache: Vec<u8>,
+        #[js(flatten)]
+        meta: Meta,
+    }
+
+    #[test]
+    fn derive_conversions() {
+        let ctx = DukContext::new().unwrap();
+        let mut tags = HashMap::new();
+        tags.insert("fast".to_owned(), true);
+        let config = Config {
+            name: "test".to_owned(),
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(1.5),
+                Shape::Rect(2.0, 3.0),
+                Shape::Poly {
+                    points: vec![0.0, 1.0],
+                },
+            ],
+            pair: Pair("a".to_owned(), 1),
+            tags,
+            scale: None,
+            cache: vec![1, 2, 3],
+            meta: Meta { version: 2 },
+        };
+
+        ctx.push_global_object();
+        ctx.push(config).unwrap();
+        ctx.put_prop_string(-2, "config").pop(1);
+        let json: String = ctx.eval("JSON.stringify(config)").unwrap().getp().unwrap();
+        assert_eq!(
+            json,
+            r#"{"displayName":"test","shapes":["Empty",{"circle":1.5},{"Rect":[2,3]},{"Poly":{"points":[0,1]}}],"pair":["a",1],"tags":{"fast":true},"scale":null,"version":2}"#
+        );
+
+        let back: Config = ctx
+            .eval("delete config.scale; config")
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert_eq!(back.name, "test");
+        assert_eq!(
+            back.shapes[3],
+            Shape::Poly {
+                points: vec![0.0, 1.0]
+            }
+        );
+        assert_eq!(back.pair, Pair("a".to_owned(), 1));
+        assert_eq!(back.scale, None);
+        assert!(back.cache.is_empty());
+        assert_eq!(back.meta, Meta { version: 2 });
+
+        let err = ctx
+            .eval("({ displayName: 'x', shapes: [{ Circle: 1 }] })")
+            .unwrap()
+            .getp::<Config>()
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::TypeError(m) => {
+                assert_eq!(m, "Config.shapes[0]: unknown variant `Circle`")
+            }
+            k => panic!("unexpected error: {}", k),
+        }
+    }
+
+    #[test]
+    fn derive_generics() {
+        let ctx = DukContext::new().unwrap();
+        ctx.push(Wrap(Meta { version: 3 })).unwrap();
+        let back: Wrap<Meta> = ctx.getp().unwrap();
+        assert_eq!(back, Wrap(Meta { version: 3 }));
+
+        let err = ctx
+            .eval("[{ version: 'x' }]")
+            .unwrap()
+            .getp::<Wrap<Meta>>()
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::TypeError(m) => assert!(m.starts_with("Wrap[0].version: "), "{}", m),
+            k => panic!("unexpected error: {}", k),
+        }
+    }
+
+    #[test]
+    fn derive_passes_other_errors() {
+        let ctx = DukContext::new().unwrap();
+        ctx.eval("[1]").unwrap();
+        match ctx.get::<Wrap<Limited>>(-1).unwrap_err().kind() {
+            ErrorKind::InsufficientMemory => {}
+            k => panic!("unexpected error: {}", k),
+        }
+
+        // Script errors keep their exception, without a path
+        let err = ctx.get::<Wrap<Throwing>>(-1).unwrap_err();
+        match err.kind() {
+            ErrorKind::TypeError(m) => assert_eq!(m, "getter"),
+            k => panic!("unexpected error: {}", k),
+        }
+        assert_eq!(err.exception().unwrap().message, "getter");
+        ctx.pop(1);
+        assert_eq!(ctx.top(), 0);
+    }
+}
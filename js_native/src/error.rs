@@ -1,5 +1,11 @@
+use super::ctx::DukContext;
+use super::types::{PersistentRef, Ref, ToDuktape};
+use std::error;
+use std::fmt;
 use std::io;
+use std::mem::ManuallyDrop;
 use std::str;
+use std::thread::{self, ThreadId};
 
 error_chain! {
     errors {
@@ -11,6 +17,14 @@ error_chain! {
             description("Insufficient Memory")
             display("Insufficient Memory")
         }
+        Timeout {
+            description("Timeout")
+            display("Execution timed out")
+        }
+        UnbalancedStack(expected: i32, found: i32) {
+            description("UnbalancedStack")
+            display("Native function left the stack unbalanced: expected top {}, found {}", expected, found)
+        }
         TypeError(message: String) {
             description("TypeError")
             display("Type error: {}", message)
@@ -25,10 +39,40 @@ error_chain! {
             display("Eval error: {}", message)
         }
 
+        RangeError(message: String) {
+            description("RangeError")
+            display("Range error: {}", message)
+        }
+
+        SyntaxError(message: String) {
+            description("SyntaxError")
+            display("Syntax error: {}", message)
+        }
+
+        URIError(message: String) {
+            description("URIError")
+            display("URI error: {}", message)
+        }
+
         Error(message: String) {
             description("Error")
             display("Error: {}", message)
         }
+
+        IncompatibleBytecode(message: String) {
+            description("IncompatibleBytecode")
+            display("Incompatible bytecode: {}", message)
+        }
+
+        JsException(exception: JsException) {
+            description("JsException")
+            display("{}", exception)
+        }
+
+        Throw(value: ThrownValue) {
+            description("Throw")
+            display("Uncaught script value")
+        }
     }
 
     foreign_links {
@@ -49,5 +93,132 @@ impl Error {
     err_impl!(type_err, TypeError);
     err_impl!(ref_err, ReferenceError);
     err_impl!(eval_err, EvalError);
+    err_impl!(range_err, RangeError);
+    err_impl!(syntax_err, SyntaxError);
+    err_impl!(uri_err, URIError);
     err_impl!(err, Error);
 }
+
+impl Error {
+    /// Throw `value` as is when returned from a callable or a method.
+    /// The value is kept alive until the error is dropped.
+    pub fn throw<T: ToDuktape>(ctx: &DukContext, value: T) -> Error {
+        if let Err(e) = value.to_context(ctx) {
+            return e;
+        }
+        let value = PersistentRef::new(ctx, -1);
+        ctx.pop(1);
+        match value {
+            Ok(value) => ErrorKind::Throw(ThrownValue::new(value)).into(),
+            Err(e) => e,
+        }
+    }
+
+    /// The script exception this error originates from, if any
+    pub fn exception(&self) -> Option<&JsException> {
+        if let ErrorKind::JsException(ref e) = *self.kind() {
+            return Some(e);
+        }
+        self.1
+            .next_error
+            .as_ref()
+            .and_then(|e| e.downcast_ref::<JsException>())
+    }
+}
+
+/// A value passed to `Error::throw` or thrown by a script, released along
+/// with the error
+pub struct ThrownValue {
+    value: ManuallyDrop<PersistentRef>,
+    thread: ThreadId,
+}
+
+// Errors must be `Send`, the value is only touched on the thread of its heap
+unsafe impl Send for ThrownValue {}
+
+impl ThrownValue {
+    pub(crate) fn new(value: PersistentRef) -> ThrownValue {
+        ThrownValue {
+            value: ManuallyDrop::new(value),
+            thread: thread::current().id(),
+        }
+    }
+
+    /// The value, unless the error was sent away from the thread of its heap
+    pub(crate) fn get(&self) -> Option<&PersistentRef> {
+        if thread::current().id() == self.thread {
+            Some(&self.value)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for ThrownValue {
+    fn drop(&mut self) {
+        // Leaked when dropped on another thread, as the heap cannot be
+        // touched from there
+        if self.get().is_some() {
+            unsafe { ManuallyDrop::drop(&mut self.value) };
+        }
+    }
+}
+
+impl fmt::Debug for ThrownValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ThrownValue")
+    }
+}
+
+/// A value thrown by a script
+#[derive(Debug)]
+pub struct JsException {
+    /// Constructor name of the error, e.g. `SyntaxError`.
+    /// Empty when the thrown value is not an error.
+    pub name: String,
+    pub message: String,
+    pub stack: Option<String>,
+    pub file_name: Option<String>,
+    pub line_number: Option<u32>,
+    pub(crate) value: Option<ThrownValue>,
+}
+
+impl JsException {
+    /// The thrown value itself, kept alive until the exception is dropped.
+    /// `None` for contexts of another heap or thread.
+    pub fn value<'a>(&self, ctx: &'a DukContext) -> Option<Ref<'a>> {
+        self.value
+            .as_ref()
+            .and_then(ThrownValue::get)
+            .and_then(|value| value.to_ref(ctx).ok())
+    }
+
+    pub(crate) fn into_error(self) -> Error {
+        let message = self.message.clone();
+        let kind = match self.name.as_str() {
+            "TypeError" => ErrorKind::TypeError(message),
+            "ReferenceError" => ErrorKind::ReferenceError(message),
+            "EvalError" => ErrorKind::EvalError(message),
+            "RangeError" => ErrorKind::RangeError(message),
+            "SyntaxError" => ErrorKind::SyntaxError(message),
+            "URIError" => ErrorKind::URIError(message),
+            _ => return ErrorKind::JsException(self).into(),
+        };
+        Error::with_chain(self, kind)
+    }
+}
+
+impl fmt::Display for JsException {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref stack) = self.stack {
+            return write!(f, "{}", stack);
+        }
+        if self.name.is_empty() {
+            write!(f, "Uncaught {}", self.message)
+        } else {
+            write!(f, "{}: {}", self.name, self.message)
+        }
+    }
+}
+
+impl error::Error for JsException {}
@@ -0,0 +1,204 @@
+//!
+//! Cross thread access to a context
+//!
+//! A `DukContext` may only be used from the thread which created it.
+//! `ContextHandle` queues jobs from any thread, which the owning thread
+//! runs with `DukContext::run_jobs`, typically once per frame of its
+//! event loop.
+//!
+
+use super::ctx::DukContext;
+use super::error::{Error, ErrorKind, Result};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use typemap::Key;
+
+type Job = Box<dyn FnOnce(&DukContext) + Send>;
+
+struct Jobs;
+
+impl Key for Jobs {
+    type Value = JobQueue;
+}
+
+struct JobQueue {
+    sender: Arc<Mutex<Sender<Job>>>,
+    receiver: Receiver<Job>,
+}
+
+/// Posts jobs to a context from any thread
+///
+/// Posting fails once the context is dropped.
+#[derive(Clone)]
+pub struct ContextHandle {
+    sender: Arc<Mutex<Sender<Job>>>,
+}
+
+impl ContextHandle {
+    /// Queue `job` on the thread owning the context. Its return value is
+    /// sent through the returned `JobResult`.
+    pub fn post<F, T>(&self, job: F) -> Result<JobResult<T>>
+    where
+        F: 'static + FnOnce(&DukContext) -> T + Send,
+        T: 'static + Send,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.execute(move |ctx| {
+            // The caller may not wait for the result
+            let _ = sender.send(job(ctx));
+        })?;
+        Ok(JobResult { receiver })
+    }
+
+    /// Queue `job` on the thread owning the context, ignoring its result
+    pub fn execute<F>(&self, job: F) -> Result<()>
+    where
+        F: 'static + FnOnce(&DukContext) + Send,
+    {
+        let sender = self
+            .sender
+            .lock()
+            .map_err(|_| ErrorKind::Error("context handle is poisoned".to_owned()))?;
+        sender
+            .send(Box::new(job))
+            .map_err(|_| ErrorKind::Error("context was dropped".to_owned()).into())
+    }
+}
+
+/// Result of a job posted with `ContextHandle::post`
+///
+/// Receiving fails if the context was dropped before running the job.
+pub struct JobResult<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> JobResult<T> {
+    /// Block until the job ran
+    pub fn wait(self) -> Result<T> {
+        self.receiver.recv().map_err(|_| dropped())
+    }
+
+    /// Block until the job ran, for at most `timeout`
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<Option<T>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(value) => Ok(Some(value)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(dropped()),
+        }
+    }
+
+    /// The result of the job, if it already ran
+    pub fn try_get(&self) -> Result<Option<T>> {
+        match self.receiver.try_recv() {
+            Ok(value) => Ok(Some(value)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(dropped()),
+        }
+    }
+}
+
+fn dropped() -> Error {
+    ErrorKind::Error("context was dropped before running the job".to_owned()).into()
+}
+
+pub(crate) fn handle(ctx: &DukContext) -> Result<ContextHandle> {
+    let queue = ctx.data_mut()?.entry::<Jobs>().or_insert_with(|| {
+        let (sender, receiver) = mpsc::channel();
+        JobQueue {
+            sender: Arc::new(Mutex::new(sender)),
+            receiver,
+        }
+    });
+
+    Ok(ContextHandle {
+        sender: queue.sender.clone(),
+    })
+}
+
+/// Run the queued jobs, returning how many ran. Jobs queued meanwhile
+/// are left for the next call.
+pub(crate) fn run_jobs(ctx: &DukContext) -> Result<usize> {
+    // Jobs may use the context data, so none of it is borrowed while they run
+    let jobs: Vec<Job> = match ctx.data()?.get::<Jobs>() {
+        Some(queue) => queue.receiver.try_iter().collect(),
+        None => return Ok(0),
+    };
+
+    let count = jobs.len();
+    for job in jobs {
+        job(ctx);
+    }
+    Ok(count)
+}
+
+/// Block until a job is posted or `timeout` elapsed, then run the queued
+/// jobs, returning how many ran
+pub(crate) fn wait_jobs(ctx: &DukContext, timeout: Option<Duration>) -> Result<usize> {
+    handle(ctx)?;
+    let job = match ctx.data()?.get::<Jobs>() {
+        // The queue holds a sender itself, so it never disconnects
+        Some(queue) => match timeout {
+            Some(timeout) => match queue.receiver.recv_timeout(timeout) {
+                Ok(job) => job,
+                Err(_) => return Ok(0),
+            },
+            None => queue.receiver.recv().map_err(|_| dropped())?,
+        },
+        None => return Ok(0),
+    };
+    job(ctx);
+    Ok(1 + run_jobs(ctx)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn post_from_threads() {
+        let ctx = DukContext::new().unwrap();
+        ctx.eval("var total = 0;").unwrap().pop(1);
+        let handle = ctx.handle().unwrap();
+
+        let workers: Vec<_> = (1..=4)
+            .map(|i| {
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    handle
+                        .post(move |ctx| {
+                            ctx.eval(format!("total += {}", i))
+                                .unwrap()
+                                .getp::<i32>()
+                                .unwrap()
+                        })
+                        .unwrap()
+                })
+            })
+            .collect();
+        let results: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+
+        assert!(results[0].try_get().unwrap().is_none());
+        assert_eq!(ctx.run_jobs().unwrap(), 4);
+        assert_eq!(ctx.run_jobs().unwrap(), 0);
+
+        let mut seen: Vec<i32> = results.into_iter().map(|r| r.wait().unwrap()).collect();
+        seen.sort();
+        assert_eq!(*seen.last().unwrap(), 10);
+
+        let total: i32 = ctx.eval("total").unwrap().getp().unwrap();
+        assert_eq!(total, 10);
+    }
+
+    #[test]
+    fn dropped_context() {
+        let ctx = DukContext::new().unwrap();
+        let handle = ctx.handle().unwrap();
+        let pending = handle.post(|_| 1).unwrap();
+        drop(ctx);
+
+        assert!(pending.wait().is_err());
+        assert!(handle.execute(|_| {}).is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//!
+//! Heap memory management
+//!
+//! Every allocation made by Duktape goes through `Heap`, which prefixes it
+//! with a small header recording its size. This lets us keep track of the
+//! current and peak usage, and refuse allocations above a hard limit.
+//!
+
+use super::error::{ErrorKind, Result};
+#[cfg(feature = "exec-timeout")]
+use super::interrupt::Interrupt;
+use dukbind::*;
+use std::alloc::{self, Layout};
+use std::cell::Cell;
+use std::ffi::{c_void, CStr};
+use std::mem;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::ptr;
+use std::rc::Rc;
+
+/// Alignment of every block handed out to duktape
+const ALIGN: usize = 16;
+/// Size of the header storing the block size, keeps the payload aligned
+const HEADER: usize = 16;
+
+/// Allocation hooks used by a heap
+///
+/// All functions must return memory aligned to at least 16 bytes, and
+/// null on failure.
+pub trait Allocator {
+    unsafe fn alloc(&self, size: usize) -> *mut u8;
+    unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8;
+    unsafe fn free(&self, ptr: *mut u8, size: usize);
+}
+
+/// Allocator backed by the global Rust allocator
+pub struct System;
+
+impl Allocator for System {
+    unsafe fn alloc(&self, size: usize) -> *mut u8 {
+        alloc::alloc(Layout::from_size_align_unchecked(size, ALIGN))
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+        alloc::realloc(
+            ptr,
+            Layout::from_size_align_unchecked(old_size, ALIGN),
+            new_size,
+        )
+    }
+
+    unsafe fn free(&self, ptr: *mut u8, size: usize) {
+        alloc::dealloc(ptr, Layout::from_size_align_unchecked(size, ALIGN))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryStats {
+    /// Bytes currently allocated by the heap
+    pub current: usize,
+    /// Highest value `current` has reached
+    pub peak: usize,
+    /// Hard limit of the heap, if any
+    pub limit: Option<usize>,
+}
+
+/// Heap user data, owned by the `DukContext` which created the heap
+pub(crate) struct Heap {
+    allocator: Box<dyn Allocator>,
+    limit: Option<usize>,
+    current: Cell<usize>,
+    peak: Cell<usize>,
+    limit_hit: Cell<bool>,
+    /// Number of protected calls made by Rust code which did not return yet
+    depth: Cell<u32>,
+    fatal: Option<Box<dyn Fn(&str)>>,
+    /// Whether native functions must leave only their return values
+    pub(crate) check_stack: bool,
+    /// Context the heap was created with, null once it is being destroyed.
+    /// Shared with values outliving the borrow of a context.
+    pub(crate) root: Rc<Cell<*mut duk_context>>,
+    #[cfg(feature = "exec-timeout")]
+    pub(crate) interrupt: Interrupt,
+}
+
+impl Heap {
+    pub(crate) fn new(
+        allocator: Box<dyn Allocator>,
+        limit: Option<usize>,
+        fatal: Option<Box<dyn Fn(&str)>>,
+        check_stack: bool,
+    ) -> Heap {
+        Heap {
+            allocator,
+            limit,
+            current: Cell::new(0),
+            peak: Cell::new(0),
+            limit_hit: Cell::new(false),
+            depth: Cell::new(0),
+            fatal,
+            check_stack,
+            root: Rc::new(Cell::new(ptr::null_mut())),
+            #[cfg(feature = "exec-timeout")]
+            interrupt: Interrupt::new(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            current: self.current.get(),
+            peak: self.peak.get(),
+            limit: self.limit,
+        }
+    }
+
+    /// Returns whether an allocation was refused since the last call
+    pub(crate) fn take_limit_hit(&self) -> bool {
+        self.limit_hit.replace(false)
+    }
+
+    /// Mark a memory error as raised by the limit, e.g. when it is rethrown
+    /// to the script of an outer call
+    pub(crate) fn set_limit_hit(&self) {
+        self.limit_hit.set(true);
+    }
+
+    /// Start a protected call. Memory errors of earlier calls were either
+    /// taken or caught by their scripts, so they are forgotten.
+    pub(crate) fn enter(&self) {
+        self.limit_hit.set(false);
+        self.depth.set(self.depth.get() + 1);
+    }
+
+    pub(crate) fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    /// Whether the running protected call is not nested in another one
+    #[cfg(feature = "exec-timeout")]
+    pub(crate) fn is_outermost(&self) -> bool {
+        self.depth.get() <= 1
+    }
+
+    fn reserve(&self, size: usize) -> bool {
+        let current = self.current.get() + size;
+        if let Some(limit) = self.limit {
+            if current > limit {
+                self.limit_hit.set(true);
+                return false;
+            }
+        }
+        self.current.set(current);
+        if current > self.peak.get() {
+            self.peak.set(current);
+        }
+        true
+    }
+
+    fn release(&self, size: usize) {
+        self.current.set(self.current.get() - size);
+    }
+}
+
+/// Create a new heap allocating through `heap`
+///
+/// The heap takes ownership of `heap`, which is released by `destroy_heap`.
+pub(crate) unsafe fn create_heap(heap: Heap) -> *mut duk_context {
+    let udata = Box::into_raw(Box::new(heap));
+    let ctx = duk_create_heap(
+        Some(heap_alloc),
+        Some(heap_realloc),
+        Some(heap_free),
+        udata as *mut c_void,
+        Some(heap_fatal),
+    );
+    if ctx.is_null() {
+        drop(Box::from_raw(udata));
+    } else {
+        (*udata).root.set(ctx);
+    }
+    ctx
+}
+
+/// Destroy a heap created with `create_heap`
+pub(crate) unsafe fn destroy_heap(ctx: *mut duk_context) {
+    let udata = get_heap(ctx).map(|h| {
+        h.root.set(ptr::null_mut());
+        h as *const Heap as *mut Heap
+    });
+    duk_destroy_heap(ctx);
+    if let Some(udata) = udata {
+        drop(Box::from_raw(udata));
+    }
+}
+
+/// Get the `Heap` of a context, if it was created with `create_heap`
+pub(crate) unsafe fn get_heap<'a>(ctx: *mut duk_context) -> Option<&'a Heap> {
+    let mut funcs: duk_memory_functions = mem::zeroed();
+    duk_get_memory_functions(ctx, &mut funcs);
+    let ours: unsafe extern "C" fn(*mut c_void, duk_size_t) -> *mut c_void = heap_alloc;
+    match funcs.alloc_func {
+        Some(f) if f as usize == ours as usize && !funcs.udata.is_null() => {
+            Some(&*(funcs.udata as *const Heap))
+        }
+        _ => None,
+    }
+}
+
+/// Make a native function which returned `ret` values fail, if the heap
+/// checks the stack and anything else is left above `base`
+pub(crate) unsafe fn check_stack(ctx: *mut duk_context, base: i32, ret: i32) -> Result<i32> {
+    match get_heap(ctx) {
+        Some(heap) if heap.check_stack && ret >= 0 => {
+            let top = duk_get_top(ctx);
+            if top != base + ret {
+                return Err(ErrorKind::UnbalancedStack(base + ret, top).into());
+            }
+            Ok(ret)
+        }
+        _ => Ok(ret),
+    }
+}
+
+unsafe extern "C" fn heap_alloc(udata: *mut c_void, size: duk_size_t) -> *mut c_void {
+    let heap = &*(udata as *const Heap);
+    if size == 0 || !heap.reserve(size) {
+        return ptr::null_mut();
+    }
+
+    let block = heap.allocator.alloc(size + HEADER);
+    if block.is_null() {
+        heap.release(size);
+        return ptr::null_mut();
+    }
+
+    *(block as *mut usize) = size;
+    block.add(HEADER) as *mut c_void
+}
+
+unsafe extern "C" fn heap_realloc(
+    udata: *mut c_void,
+    ptr: *mut c_void,
+    size: duk_size_t,
+) -> *mut c_void {
+    if ptr.is_null() {
+        return heap_alloc(udata, size);
+    }
+    if size == 0 {
+        heap_free(udata, ptr);
+        return ptr::null_mut();
+    }
+
+    let heap = &*(udata as *const Heap);
+    let block = (ptr as *mut u8).sub(HEADER);
+    let old_size = *(block as *mut usize);
+
+    if size > old_size && !heap.reserve(size - old_size) {
+        return ptr::null_mut();
+    }
+
+    let block = heap
+        .allocator
+        .realloc(block, old_size + HEADER, size + HEADER);
+    if block.is_null() {
+        if size > old_size {
+            heap.release(size - old_size);
+        }
+        return ptr::null_mut();
+    }
+
+    if size < old_size {
+        heap.release(old_size - size);
+    }
+
+    *(block as *mut usize) = size;
+    block.add(HEADER) as *mut c_void
+}
+
+unsafe extern "C" fn heap_free(udata: *mut c_void, ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+
+    let heap = &*(udata as *const Heap);
+    let block = (ptr as *mut u8).sub(HEADER);
+    let size = *(block as *mut usize);
+    heap.allocator.free(block, size + HEADER);
+    heap.release(size);
+}
+
+/// Duktape requires the fatal handler to never return
+unsafe extern "C" fn heap_fatal(udata: *mut c_void, msg: *const c_char) {
+    let msg = if msg.is_null() {
+        "unknown fatal error".into()
+    } else {
+        CStr::from_ptr(msg).to_string_lossy()
+    };
+
+    let heap = &*(udata as *const Heap);
+    match heap.fatal {
+        Some(ref handler) => {
+            // Unwinding into duktape is not an option either
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(&msg)));
+        }
+        None => eprintln!("duktape fatal error: {}", msg),
+    }
+
+    process::abort();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ctx::DukContext;
+    use crate::error::ErrorKind;
+
+    struct Counting(Rc<Cell<usize>>);
+
+    impl Allocator for Counting {
+        unsafe fn alloc(&self, size: usize) -> *mut u8 {
+            self.0.set(self.0.get() + 1);
+            System.alloc(size)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+            System.realloc(ptr, old_size, new_size)
+        }
+
+        unsafe fn free(&self, ptr: *mut u8, size: usize) {
+            System.free(ptr, size)
+        }
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let count = Rc::new(Cell::new(0));
+        let ctx = DukContext::builder()
+            .allocator(Counting(count.clone()))
+            .build()
+            .unwrap();
+        ctx.eval("var a = []; for (var i = 0; i < 100; i++) a.push({ i: i });")
+            .unwrap();
+
+        assert!(count.get() > 0);
+
+        let stats = ctx.memory_stats().unwrap();
+        assert!(stats.current > 0);
+        assert!(stats.peak >= stats.current);
+        assert_eq!(stats.limit, None);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let ctx = DukContext::builder()
+            .memory_limit(1024 * 1024)
+            .build()
+            .unwrap();
+
+        let caught: bool = ctx
+            .eval(
+                r#"
+                var caught = false;
+                try {
+                    var a = [];
+                    while (true) a.push(new Array(1024).join('x') + a.length);
+                } catch (e) {
+                    caught = e instanceof RangeError;
+                }
+                a = null;
+                caught
+                "#,
+            )
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert!(caught);
+
+        // The caught failure is not blamed for later errors
+        let err = ctx.eval("throw new RangeError('own')").unwrap_err();
+        assert!(!matches!(err.kind(), ErrorKind::InsufficientMemory));
+
+        match ctx.eval("var b = []; while (true) b.push(new Array(1024).join('y') + b.length);") {
+            Err(e) => match e.kind() {
+                ErrorKind::InsufficientMemory => {}
+                k => panic!("unexpected error: {}", k),
+            },
+            Ok(_) => panic!("script should run out of memory"),
+        }
+
+        let stats = ctx.memory_stats().unwrap();
+        assert!(stats.peak <= 1024 * 1024);
+    }
+}
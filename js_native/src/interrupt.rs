@@ -0,0 +1,263 @@
+//!
+//! Execution timeouts
+//!
+//! Duktape polls `DUK_USE_EXEC_TIMEOUT_CHECK(udata)` while executing
+//! bytecode. The engine must be configured with
+//!
+//! ```c
+//! #define DUK_USE_EXEC_TIMEOUT_CHECK duk_rs_exec_timeout_check
+//! ```
+//!
+//! for deadlines, step budgets and `InterruptHandle` to have any effect.
+//! Setting them fails on engines built without the hook.
+//!
+
+use super::ctx::DukContext;
+use super::heap::Heap;
+use dukbind::duk_bool_t;
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Whether the engine calls `duk_rs_exec_timeout_check`
+static ENGINE: AtomicU8 = AtomicU8::new(UNKNOWN);
+const UNKNOWN: u8 = 0;
+const HOOKED: u8 = 1;
+const MISSING: u8 = 2;
+
+/// Runs enough bytecode for duktape to check for timeouts at least once
+static PROBE: &'static str = "for (var i = 0; i < 500000; i++) {}";
+
+/// Whether the engine was built with the timeout hook. Duktape has no way
+/// to tell, so the first call runs a short loop and looks for the hook
+/// being called.
+pub(crate) fn supported(ctx: &DukContext) -> bool {
+    match ENGINE.load(Ordering::SeqCst) {
+        HOOKED => return true,
+        MISSING => return false,
+        _ => {}
+    }
+    if ctx.eval(PROBE).is_ok() {
+        ctx.pop(1);
+    }
+    // Set by the hook meanwhile if it exists
+    let _ = ENGINE.compare_exchange(UNKNOWN, MISSING, Ordering::SeqCst, Ordering::SeqCst);
+    ENGINE.load(Ordering::SeqCst) == HOOKED
+}
+
+pub(crate) struct Interrupt {
+    requested: Arc<AtomicBool>,
+    deadline: Cell<Option<Instant>>,
+    budget: Cell<Option<u64>>,
+    fired: Cell<bool>,
+}
+
+impl Interrupt {
+    pub(crate) fn new() -> Interrupt {
+        Interrupt {
+            requested: Arc::new(AtomicBool::new(false)),
+            deadline: Cell::new(None),
+            budget: Cell::new(None),
+            fired: Cell::new(false),
+        }
+    }
+
+    pub(crate) fn handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            requested: self.requested.clone(),
+        }
+    }
+
+    pub(crate) fn set_deadline(&self, deadline: Option<Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    pub(crate) fn set_budget(&self, steps: Option<u64>) {
+        self.budget.set(steps);
+    }
+
+    /// Whether the running script was aborted and the error did not reach
+    /// the host yet
+    pub(crate) fn fired(&self) -> bool {
+        self.fired.get()
+    }
+
+    /// Called once the error reached the host. Limits which fired are cleared.
+    pub(crate) fn clear(&self) {
+        if !self.fired.replace(false) {
+            return;
+        }
+
+        self.requested.store(false, Ordering::SeqCst);
+        if self.deadline.get().map_or(false, |d| Instant::now() >= d) {
+            self.deadline.set(None);
+        }
+        if self.budget.get() == Some(0) {
+            self.budget.set(None);
+        }
+    }
+
+    fn check(&self) -> bool {
+        // Keep firing until the error reaches the host, so scripts cannot
+        // swallow the timeout with a catch block, nor Rust functions they
+        // call by dropping the error.
+        if self.fired.get() {
+            return true;
+        }
+
+        let fired = if self.requested.load(Ordering::SeqCst) {
+            true
+        } else if self.deadline.get().map_or(false, |d| Instant::now() >= d) {
+            true
+        } else {
+            match self.budget.get() {
+                Some(0) => true,
+                Some(n) => {
+                    self.budget.set(Some(n - 1));
+                    false
+                }
+                None => false,
+            }
+        };
+
+        self.fired.set(fired);
+        fired
+    }
+}
+
+/// Requests the abortion of the script running on a context, from any thread
+///
+/// If no script is running, the next one is aborted instead.
+#[derive(Clone)]
+pub struct InterruptHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Hook for `DUK_USE_EXEC_TIMEOUT_CHECK`, called with the heap user data
+#[no_mangle]
+pub unsafe extern "C" fn duk_rs_exec_timeout_check(udata: *mut c_void) -> duk_bool_t {
+    if udata.is_null() {
+        return 0;
+    }
+    ENGINE.store(HOOKED, Ordering::SeqCst);
+    let heap = &*(udata as *const Heap);
+    if heap.interrupt.check() {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ctx::DukContext;
+    use crate::error::{ErrorKind, Result};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn assert_timeout(ctx: &DukContext, script: &str) {
+        match ctx.eval(script) {
+            Err(e) => match e.kind() {
+                ErrorKind::Timeout => {}
+                k => panic!("unexpected error: {}", k),
+            },
+            Ok(_) => panic!("script should time out"),
+        }
+    }
+
+    /// Limits can only be set on engines built with the hook
+    fn hooked(ctx: &DukContext) -> bool {
+        if super::supported(ctx) {
+            return true;
+        }
+        assert!(ctx.set_step_budget(Some(10)).is_err());
+        assert!(ctx
+            .set_execution_timeout(Some(Duration::from_secs(1)))
+            .is_err());
+        assert!(ctx.interrupt_handle().is_err());
+        assert!(ctx.set_step_budget(None).is_ok());
+        false
+    }
+
+    static SPIN: &'static str = "while (true) { try { for (;;) {} } catch (e) {} }";
+
+    #[test]
+    fn deadline() {
+        let ctx = DukContext::new().unwrap();
+        if !hooked(&ctx) {
+            return;
+        }
+        ctx.set_execution_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let start = Instant::now();
+        assert_timeout(&ctx, SPIN);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // The deadline is cleared once it fired
+        ctx.eval("1 + 1").unwrap();
+    }
+
+    #[test]
+    fn step_budget() {
+        let ctx = DukContext::new().unwrap();
+        if !hooked(&ctx) {
+            return;
+        }
+        ctx.set_step_budget(Some(10)).unwrap();
+        assert_timeout(&ctx, SPIN);
+        ctx.eval("1 + 1").unwrap();
+    }
+
+    #[test]
+    fn interrupt_handle() {
+        let ctx = DukContext::new().unwrap();
+        if !hooked(&ctx) {
+            return;
+        }
+        let handle = ctx.interrupt_handle().unwrap();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.interrupt();
+        });
+        assert_timeout(&ctx, SPIN);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn nested_calls() {
+        let ctx = DukContext::new().unwrap();
+        if !hooked(&ctx) {
+            return;
+        }
+        ctx.push_global_object()
+            .push_function((0, |ctx: &DukContext| -> Result<i32> {
+                ctx.eval("for (;;) {}")?;
+                Ok(1)
+            }))
+            .put_prop_string(-2, "rethrow")
+            .push_function((0, |ctx: &DukContext| -> Result<i32> {
+                assert!(ctx.eval("for (;;) {}").is_err());
+                Ok(0)
+            }))
+            .put_prop_string(-2, "swallow")
+            .pop(1);
+
+        // Neither the script calling the function, nor the function itself
+        // can keep running once the limit fired in the inner call
+        ctx.set_step_budget(Some(10)).unwrap();
+        assert_timeout(&ctx, "while (true) { try { rethrow(); } catch (e) {} }");
+        ctx.eval("1 + 1").unwrap().pop(1);
+
+        ctx.set_step_budget(Some(10)).unwrap();
+        assert_timeout(&ctx, "while (true) { swallow(); }");
+        ctx.eval("1 + 1").unwrap().pop(1);
+    }
+}
@@ -8,28 +8,64 @@ extern crate typemap;
 #[macro_use]
 extern crate bitflags;
 extern crate dukbind;
+#[cfg(feature = "derive")]
+extern crate js_derive;
+// Lets derives expand to `::js_native` paths inside the crate
+#[cfg(all(test, feature = "derive"))]
+extern crate self as js_native;
 
 mod callable;
 pub mod class;
+#[cfg(feature = "serde")]
+pub mod convert;
 mod ctx;
+mod debugger;
+#[doc(hidden)]
+pub mod derive;
 pub mod error;
+mod handle;
+mod heap;
+#[cfg(feature = "exec-timeout")]
+mod interrupt;
 mod macros;
+pub mod modules;
 mod privates;
+mod promise;
+mod scope;
+pub mod sourcemap;
 pub mod types;
 
-pub use self::callable::Callable;
+pub use self::callable::{typed, Argument, Callable, Rest, Typed};
+#[cfg(feature = "serde")]
+pub use self::convert::{from_value, to_value};
 pub use self::ctx::*;
+pub use self::handle::{ContextHandle, JobResult};
+pub use self::promise::{Async, JsFuture};
+pub use self::heap::{Allocator, MemoryStats, System};
+pub use self::scope::{Scope, StackValue};
+#[cfg(feature = "derive")]
+pub use js_derive::{js_methods, FromDuktape, JsClass, ToDuktape};
+#[cfg(feature = "exec-timeout")]
+pub use self::interrupt::InterruptHandle;
 pub use self::macros::*;
 pub use self::typemap::Key;
 
 pub mod prelude {
-    pub use super::callable::Callable;
+    pub use super::callable::{typed, Argument, Callable, Rest, Typed};
     pub use super::class;
     pub use super::ctx::*;
     pub use super::error::Error as DukError;
     pub use super::error::ErrorKind as DukErrorKind;
+    pub use super::error::JsException;
     pub use super::error::Result as DukResult;
+    pub use super::handle::{ContextHandle, JobResult};
+    #[cfg(feature = "derive")]
+    pub use super::js_derive::{js_methods, FromDuktape, JsClass, ToDuktape};
     pub use super::macros::*;
+    pub use super::modules::{FileResolver, ModuleResolver, NativeModule};
+    pub use super::promise::{Async, JsFuture};
+    pub use super::scope::{Scope, StackValue};
+    pub use super::sourcemap::SourceMap;
     pub use super::types::*;
 }
 
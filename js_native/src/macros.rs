@@ -19,23 +19,56 @@ macro_rules! duk_reference_error {
     };
 }
 
+/// Register one or more `NativeModule` types with a context
+///
+/// Every type must implement `Default`. Evaluates to `Result<()>`.
+///
+/// ```ignore
+/// register_native_module!(ctx, Console, Timer)?;
+/// ```
 #[macro_export]
 macro_rules! register_native_module {
-    ($msg: expr) => {
-       
+    ($ctx: expr, $($module: ty),+ $(,)*) => {
+        (|| -> $crate::error::Result<()> {
+            $(
+                $ctx.register_module(<$module as ::std::default::Default>::default())?;
+            )+
+            Ok(())
+        })()
     };
 }
 
 #[cfg(test)]
 mod test {
-    struct A{
+    use crate::prelude::*;
 
-    }
+    #[derive(Default)]
+    struct A {}
 
-    register_native_module!(A);
+    impl NativeModule for A {
+        fn name(&self) -> &str {
+            "a"
+        }
+
+        fn init(&self, _ctx: &DukContext, exports: &Object) -> DukResult<()> {
+            exports.set("answer", 42);
+            Ok(())
+        }
+    }
 
     #[test]
-    fn test(){
+    fn test() -> DukResult<()> {
+        let ctx = DukContext::new()?;
+        register_native_module!(ctx, A)?;
+
+        let answer: i32 = ctx.eval("require('a').answer")?.getp()?;
+        assert_eq!(answer, 42);
+
+        let same: bool = ctx.eval("require('a') === require('a')")?.getp()?;
+        assert!(same);
+
+        assert!(ctx.eval("require('b')").is_err());
 
+        Ok(())
     }
 }
@@ -0,0 +1,396 @@
+//!
+//! CommonJS module loading
+//!
+//! `require()` first looks up Rust types implementing `NativeModule`
+//! registered with the heap, then falls back to the `ModuleResolver`
+//! installed with `DukContext::set_module_resolver`.
+//!
+
+use super::ctx::{Compile, DukContext};
+use super::error::{ErrorKind, Result};
+use super::types::{FromDuktape, Object};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use typemap::Key;
+
+/// Global stash property holding the `module` object of every loaded module
+static CACHE_KEY: &'static [u8] = b"modules";
+/// Hidden property of a module's `require` function holding the module id
+static MODULE_ID_KEY: &'static [u8] = b"\xFFmoduleId";
+
+pub trait NativeModule {
+    /// The module id used with `require()`
+    fn name(&self) -> &str;
+
+    /// Populate the `exports` object of the module
+    fn init(&self, ctx: &DukContext, exports: &Object) -> Result<()>;
+}
+
+pub trait ModuleResolver {
+    /// Resolve `id`, as required from the module `parent`, into a canonical id.
+    /// `parent` is empty for requires issued from the global scope.
+    fn resolve(&self, id: &str, parent: &str) -> Result<String>;
+
+    /// Load the source of a module previously returned by `resolve`
+    fn load(&self, id: &str) -> Result<String>;
+}
+
+/// Resolves modules from the filesystem, rooted at a directory
+///
+/// Ids starting with `./` or `../` are relative to the requiring module,
+/// everything else is relative to the root. `name`, `name.js` and
+/// `name/index.js` are tried in that order. Module ids are the paths
+/// relative to the root, and may never escape it.
+pub struct FileResolver {
+    root: PathBuf,
+    extensions: Vec<String>,
+}
+
+impl FileResolver {
+    pub fn new<P: Into<PathBuf>>(root: P) -> FileResolver {
+        FileResolver {
+            root: root.into(),
+            extensions: vec!["js".to_owned()],
+        }
+    }
+
+    /// Add an extension to try when resolving, e.g. `json`
+    pub fn extension(mut self, ext: &str) -> Self {
+        self.extensions.push(ext.trim_start_matches('.').to_owned());
+        self
+    }
+
+    fn is_file(&self, id: &str) -> bool {
+        self.root.join(id).is_file()
+    }
+}
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&self, id: &str, parent: &str) -> Result<String> {
+        let mut parts: Vec<&str> = Vec::new();
+        if id.starts_with("./") || id.starts_with("../") {
+            parts.extend(parent.split('/').filter(|p| !p.is_empty()));
+            parts.pop();
+        }
+
+        for part in id.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    if parts.pop().is_none() {
+                        bail!(ErrorKind::Error(format!(
+                            "module '{}' is outside of the module root",
+                            id
+                        )));
+                    }
+                }
+                part => parts.push(part),
+            }
+        }
+
+        let base = parts.join("/");
+        if !base.is_empty() && self.is_file(&base) {
+            return Ok(base);
+        }
+        for ext in &self.extensions {
+            let file = format!("{}.{}", base, ext);
+            if self.is_file(&file) {
+                return Ok(file);
+            }
+        }
+        for ext in &self.extensions {
+            let index = if base.is_empty() {
+                format!("index.{}", ext)
+            } else {
+                format!("{}/index.{}", base, ext)
+            };
+            if self.is_file(&index) {
+                return Ok(index);
+            }
+        }
+
+        bail!(ErrorKind::Error(format!("cannot find module '{}'", id)))
+    }
+
+    fn load(&self, id: &str) -> Result<String> {
+        Ok(fs::read_to_string(self.root.join(id))?)
+    }
+}
+
+struct Registry;
+
+impl Key for Registry {
+    type Value = HashMap<String, Rc<dyn NativeModule>>;
+}
+
+struct Resolver;
+
+impl Key for Resolver {
+    type Value = Rc<dyn ModuleResolver>;
+}
+
+enum Source {
+    Native(Rc<dyn NativeModule>),
+    Script(Rc<dyn ModuleResolver>),
+}
+
+pub(crate) fn register_module(ctx: &DukContext, module: Box<dyn NativeModule>) -> Result<()> {
+    let name = module.name().to_owned();
+    ctx.data_mut()?
+        .entry::<Registry>()
+        .or_insert_with(HashMap::new)
+        .insert(name, Rc::from(module));
+
+    init_require(ctx);
+
+    Ok(())
+}
+
+pub(crate) fn set_resolver(ctx: &DukContext, resolver: Box<dyn ModuleResolver>) -> Result<()> {
+    ctx.data_mut()?.insert::<Resolver>(Rc::from(resolver));
+
+    init_require(ctx);
+
+    Ok(())
+}
+
+/// Install the global `require` function, unless it already exists
+pub(crate) fn init_require(ctx: &DukContext) {
+    ctx.push_global_stash();
+    if !ctx.has_prop_string(-1, CACHE_KEY) {
+        ctx.push_bare_object().put_prop_string(-2, CACHE_KEY);
+    }
+    ctx.pop(1);
+
+    ctx.push_global_object();
+    if !ctx.has_prop_string(-1, "require") {
+        ctx.push_function((1, require_fn))
+            .put_prop_string(-2, "require");
+    }
+    ctx.pop(1);
+}
+
+/// `require(id)` as seen by scripts. Module scoped functions carry the id
+/// of their module, so relative ids resolve against it.
+fn require_fn(ctx: &DukContext) -> Result<i32> {
+    let id = ctx.get::<String>(0)?;
+
+    ctx.push_current_function()
+        .get_prop_string(-1, MODULE_ID_KEY);
+    let parent = if ctx.is_string(-1) {
+        ctx.get_string(-1)?.to_owned()
+    } else {
+        String::new()
+    };
+    ctx.pop(2);
+
+    require(ctx, &id, &parent)?;
+    Ok(1)
+}
+
+/// Push the exports of module `id` onto the stack, loading it on first use
+pub(crate) fn require(ctx: &DukContext, id: &str, parent: &str) -> Result<()> {
+    let (key, source) = {
+        let data = ctx.data()?;
+        match data.get::<Registry>().and_then(|r| r.get(id)) {
+            Some(m) => (id.to_owned(), Source::Native(m.clone())),
+            None => match data.get::<Resolver>() {
+                Some(r) => (r.resolve(id, parent)?, Source::Script(r.clone())),
+                None => bail!(ErrorKind::Error(format!("cannot find module '{}'", id))),
+            },
+        }
+    };
+
+    ctx.push_global_stash().get_prop_string(-1, CACHE_KEY);
+    if ctx.has_prop_string(-1, &key) {
+        ctx.get_prop_string(-1, &key)
+            .get_prop_string(-1, "exports")
+            .remove(-2)
+            .remove(-2)
+            .remove(-2);
+        return Ok(());
+    }
+    ctx.pop(2);
+
+    ctx.push_object()
+        .push_string(&key)
+        .put_prop_string(-2, "id")
+        .push_object()
+        .put_prop_string(-2, "exports")
+        .push_boolean(false)
+        .put_prop_string(-2, "loaded");
+
+    // Cache the module before running it, so cyclic requires see the
+    // partially initialized exports instead of recursing forever.
+    ctx.push_global_stash()
+        .get_prop_string(-1, CACHE_KEY)
+        .dup(-3)
+        .put_prop_string(-2, &key)
+        .pop(2);
+
+    let ret = match source {
+        Source::Native(module) => {
+            ctx.get_prop_string(-1, "exports");
+            let exports = Object::from_context(ctx, -1)?;
+            ctx.pop(1);
+            module.init(ctx, &exports)
+        }
+        Source::Script(resolver) => run_script(ctx, &*resolver, &key),
+    };
+
+    if let Err(e) = ret {
+        ctx.pop(1);
+        ctx.push_global_stash()
+            .get_prop_string(-1, CACHE_KEY)
+            .del_prop_string(-1, &key)
+            .pop(2);
+        return Err(e);
+    }
+
+    ctx.push_boolean(true)
+        .put_prop_string(-2, "loaded")
+        .get_prop_string(-1, "exports")
+        .remove(-2);
+
+    Ok(())
+}
+
+/// Run the script module `id`, with its `module` object on top of the stack
+fn run_script(ctx: &DukContext, resolver: &dyn ModuleResolver, id: &str) -> Result<()> {
+    let source = resolver.load(id)?;
+    let module = ctx.normalize_index(-1);
+
+    // Keep the wrapper on the first line so line numbers stay intact
+    let mut wrapped = String::from("(function (exports, require, module, __filename, __dirname) {");
+    wrapped.push_str(&source);
+    wrapped.push_str("\n})");
+
+    ctx.compile_string_filename(wrapped, id, Compile::EVAL)?;
+    ctx.call(0)?;
+
+    let dirname = match id.rfind('/') {
+        Some(i) => &id[..i],
+        None => "",
+    };
+
+    ctx.get_prop_string(module, "exports")
+        .get_prop_string(module, "exports")
+        .push_function((1, require_fn))
+        .push_string(id)
+        .put_prop_string(-2, MODULE_ID_KEY)
+        .dup(module)
+        .push_string(id)
+        .push_string(dirname)
+        .call_method(5)?
+        .pop(1);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    struct MemoryResolver(HashMap<&'static str, &'static str>);
+
+    impl ModuleResolver for MemoryResolver {
+        fn resolve(&self, id: &str, _parent: &str) -> Result<String> {
+            let id = id.trim_start_matches("./");
+            if self.0.contains_key(id) {
+                return Ok(id.to_owned());
+            }
+            bail!(ErrorKind::Error(format!("cannot find module '{}'", id)))
+        }
+
+        fn load(&self, id: &str) -> Result<String> {
+            Ok(self.0[id].to_owned())
+        }
+    }
+
+    #[test]
+    fn module_exports() {
+        let ctx = DukContext::new().unwrap();
+        let mut files = HashMap::new();
+        files.insert("a", "exports.name = 'a'; exports.b = require('./b').name;");
+        files.insert("b", "module.exports = { name: 'b' };");
+        ctx.set_module_resolver(MemoryResolver(files)).unwrap();
+
+        let out: String = ctx
+            .eval("var a = require('a'); a.name + a.b")
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert_eq!(out, "ab");
+
+        let cached: bool = ctx
+            .eval("require('./b') === require('b')")
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert!(cached);
+        assert!(ctx.eval("require('c')").is_err());
+    }
+
+    #[test]
+    fn module_cycles() {
+        let ctx = DukContext::new().unwrap();
+        let mut files = HashMap::new();
+        files.insert(
+            "a",
+            "exports.early = 1; var b = require('b'); exports.seen = b.seen;",
+        );
+        files.insert("b", "exports.seen = require('a').early;");
+        ctx.set_module_resolver(MemoryResolver(files)).unwrap();
+
+        let seen: i32 = ctx
+            .require("a")
+            .unwrap()
+            .getp::<Object>()
+            .unwrap()
+            .get("seen")
+            .unwrap();
+        assert_eq!(seen, 1);
+    }
+
+    #[test]
+    fn file_resolver() {
+        let root = env::temp_dir().join(format!("js_native_modules_{}", std::process::id()));
+        fs::create_dir_all(root.join("lib/util")).unwrap();
+        fs::write(
+            root.join("main.js"),
+            "module.exports = require('./lib/util').value;",
+        )
+        .unwrap();
+        fs::write(
+            root.join("lib/util/index.js"),
+            "exports.value = require('../data');",
+        )
+        .unwrap();
+        fs::write(root.join("lib/data.js"), "module.exports = __filename;").unwrap();
+
+        let resolver = FileResolver::new(&root);
+        assert_eq!(
+            resolver.resolve("./lib/util", "").unwrap(),
+            "lib/util/index.js"
+        );
+        assert_eq!(
+            resolver.resolve("../data", "lib/util/index.js").unwrap(),
+            "lib/data.js"
+        );
+        assert_eq!(
+            resolver.resolve("../../main", "lib/util/index.js").unwrap(),
+            "main.js"
+        );
+        assert!(resolver.resolve("../../../main", "lib/util/index.js").is_err());
+
+        let ctx = DukContext::new().unwrap();
+        ctx.set_module_resolver(resolver).unwrap();
+        let value: String = ctx.eval("require('main')").unwrap().getp().unwrap();
+        assert_eq!(value, "lib/data.js");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
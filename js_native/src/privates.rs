@@ -162,6 +162,33 @@ pub unsafe fn unref(ctx: *mut duk_context, refer: u32) {
     duk_pop(ctx);
 }
 
+unsafe extern "C" fn safe_call_trampoline<F: FnMut(*mut duk_context) -> duk_ret_t>(
+    ctx: *mut duk_context,
+    udata: *mut c_void,
+) -> duk_ret_t {
+    let f = &mut *(udata as *mut F);
+    f(ctx)
+}
+
+/// Run `f` in a protected call, catching any error it throws
+///
+/// `nargs` values are taken from the top of the stack, and `nrets` values
+/// are left in their place. On error the error is the first return value.
+pub unsafe fn safe_call<F: FnMut(*mut duk_context) -> duk_ret_t>(
+    ctx: *mut duk_context,
+    nargs: duk_idx_t,
+    nrets: duk_idx_t,
+    mut f: F,
+) -> duk_int_t {
+    duk_safe_call(
+        ctx,
+        Some(safe_call_trampoline::<F>),
+        &mut f as *mut F as *mut c_void,
+        nargs,
+        nrets,
+    )
+}
+
 #[allow(dead_code)]
 #[inline(always)]
 pub unsafe fn duk_create_heap_default() -> *mut duk_context {
@@ -0,0 +1,643 @@
+//!
+//! Promises backed by Rust futures
+//!
+//! Duktape has no `Promise` of its own, so `DukContext::init_promise`
+//! installs a polyfill, unless the engine already provides one. Reactions
+//! of polyfilled promises are queued as jobs, run by
+//! `DukContext::run_microtasks`.
+//!
+//! Futures returned to scripts through `Async` are polled on the thread
+//! owning the context: wakers post to the job queue of `ContextHandle`,
+//! which `DukContext::run_jobs` drains. Promises are awaited from Rust with
+//! `DukContext::to_future`.
+//!
+
+use super::ctx::DukContext;
+use super::error::Result;
+use super::handle;
+use super::privates::{make_ref, push_ref, unref};
+use super::types::{Ref, ToDuktape};
+use dukbind::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll, Wake, Waker};
+use typemap::Key;
+
+/// Global stash properties of the helpers installed by `init`
+static DRAIN_KEY: &'static str = "promiseDrain";
+static DEFER_KEY: &'static str = "promiseDefer";
+static AWAIT_KEY: &'static str = "promiseAwait";
+
+/// Returns the function running the queued promise jobs. `Promise` is only
+/// defined when missing, a built-in one runs its jobs by itself.
+static POLYFILL: &'static str = r#"(function (global) {
+    var queue = [];
+    function drain() {
+        var count = 0;
+        while (queue.length > 0) {
+            var jobs = queue;
+            queue = [];
+            for (var i = 0; i < jobs.length; i++) {
+                try { jobs[i](); } catch (e) {}
+                count++;
+            }
+        }
+        return count;
+    }
+    if (typeof global.Promise === 'function') return drain;
+
+    var PENDING = 0, FULFILLED = 1, REJECTED = 2;
+
+    function Promise(executor) {
+        if (!(this instanceof Promise)) throw new TypeError('Promise must be called with new');
+        if (typeof executor !== 'function') throw new TypeError('Promise executor is not a function');
+        this._state = PENDING;
+        this._value = undefined;
+        this._reactions = [];
+        var fns = resolvers(this);
+        try { executor(fns.resolve, fns.reject); } catch (e) { fns.reject(e); }
+    }
+
+    function resolvers(p) {
+        var done = false;
+        return {
+            resolve: function (v) { if (!done) { done = true; resolve(p, v); } },
+            reject: function (r) { if (!done) { done = true; settle(p, REJECTED, r); } }
+        };
+    }
+
+    function resolve(p, v) {
+        if (v === p) return settle(p, REJECTED, new TypeError('Promise resolved with itself'));
+        if (v !== null && (typeof v === 'object' || typeof v === 'function')) {
+            var then;
+            try { then = v.then; } catch (e) { return settle(p, REJECTED, e); }
+            if (typeof then === 'function') {
+                var fns = resolvers(p);
+                queue.push(function () {
+                    try { then.call(v, fns.resolve, fns.reject); } catch (e) { fns.reject(e); }
+                });
+                return;
+            }
+        }
+        settle(p, FULFILLED, v);
+    }
+
+    function settle(p, state, value) {
+        if (p._state !== PENDING) return;
+        p._state = state;
+        p._value = value;
+        var reactions = p._reactions;
+        p._reactions = null;
+        for (var i = 0; i < reactions.length; i++) react(p, reactions[i]);
+    }
+
+    function react(p, r) {
+        queue.push(function () {
+            var handler = p._state === FULFILLED ? r.onFulfilled : r.onRejected;
+            if (typeof handler !== 'function') {
+                (p._state === FULFILLED ? r.resolve : r.reject)(p._value);
+                return;
+            }
+            var result;
+            try { result = handler(p._value); } catch (e) { r.reject(e); return; }
+            r.resolve(result);
+        });
+    }
+
+    Promise.prototype.then = function (onFulfilled, onRejected) {
+        var r = { onFulfilled: onFulfilled, onRejected: onRejected };
+        var next = new Promise(function (resolve, reject) {
+            r.resolve = resolve;
+            r.reject = reject;
+        });
+        if (this._state === PENDING) this._reactions.push(r); else react(this, r);
+        return next;
+    };
+    Promise.prototype['catch'] = function (onRejected) {
+        return this.then(undefined, onRejected);
+    };
+    Promise.prototype['finally'] = function (f) {
+        return this.then(function (v) {
+            return Promise.resolve(f()).then(function () { return v; });
+        }, function (e) {
+            return Promise.resolve(f()).then(function () { throw e; });
+        });
+    };
+
+    Promise.resolve = function (v) {
+        if (v instanceof Promise) return v;
+        return new Promise(function (resolve) { resolve(v); });
+    };
+    Promise.reject = function (r) {
+        return new Promise(function (resolve, reject) { reject(r); });
+    };
+    Promise.all = function (items) {
+        return new Promise(function (resolve, reject) {
+            var results = [], remaining = items.length;
+            if (remaining === 0) return resolve(results);
+            for (var i = 0; i < items.length; i++) (function (i) {
+                Promise.resolve(items[i]).then(function (v) {
+                    results[i] = v;
+                    if (--remaining === 0) resolve(results);
+                }, reject);
+            })(i);
+        });
+    };
+    Promise.race = function (items) {
+        return new Promise(function (resolve, reject) {
+            for (var i = 0; i < items.length; i++) Promise.resolve(items[i]).then(resolve, reject);
+        });
+    };
+
+    Object.defineProperty(global, 'Promise', { value: Promise, writable: true, configurable: true });
+    return drain;
+})(this)"#;
+
+/// A promise with its resolving functions
+static DEFER: &'static str = r#"(function () {
+    var d = {};
+    d.promise = new Promise(function (resolve, reject) {
+        d.resolve = resolve;
+        d.reject = reject;
+    });
+    return d;
+})"#;
+
+static AWAIT: &'static str = r#"(function (value, fulfilled, rejected) {
+    Promise.resolve(value).then(fulfilled, rejected);
+})"#;
+
+/// Returns a `Future` to scripts, as a promise settled with its output
+///
+/// ```ignore
+/// ctx.push_function(typed(|url: String| Ok(Async(download(url)))));
+/// ```
+pub struct Async<F>(pub F);
+
+impl<F, T> ToDuktape for Async<F>
+where
+    F: 'static + Future<Output = Result<T>>,
+    T: 'static + ToDuktape,
+{
+    fn to_context(self, ctx: &DukContext) -> Result<()> {
+        push_future(ctx, self.0)
+    }
+}
+
+type Settle = Box<dyn FnOnce(&DukContext) -> Result<()>>;
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = Result<Settle>>>>,
+    resolve: u32,
+    reject: u32,
+}
+
+#[derive(Default)]
+struct TaskList {
+    next: u64,
+    tasks: HashMap<u64, Task>,
+}
+
+struct Tasks;
+
+impl Key for Tasks {
+    type Value = TaskList;
+}
+
+/// Polls its task again, through the job queue of the context
+struct TaskWaker {
+    handle: handle::ContextHandle,
+    id: u64,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        let id = self.id;
+        // Fails once the context is dropped, with the task
+        let _ = self.handle.execute(move |ctx| {
+            let _ = poll_task(ctx, id);
+        });
+    }
+}
+
+pub(crate) fn init(ctx: &DukContext) -> Result<()> {
+    ctx.push_global_stash();
+    let installed = ctx.has_prop_string(-1, DRAIN_KEY);
+    ctx.pop(1);
+    if installed {
+        return Ok(());
+    }
+
+    ctx.push_global_stash();
+    for &(key, source) in &[
+        (DRAIN_KEY, POLYFILL),
+        (DEFER_KEY, DEFER),
+        (AWAIT_KEY, AWAIT),
+    ] {
+        if let Err(e) = ctx.eval(source) {
+            ctx.pop(1);
+            return Err(e);
+        }
+        ctx.put_prop_string(-2, key);
+    }
+    ctx.pop(1);
+    Ok(())
+}
+
+/// Run the queued promise jobs, including the jobs they queue
+pub(crate) fn run_microtasks(ctx: &DukContext) -> Result<usize> {
+    ctx.push_global_stash()
+        .get_prop_string(-1, DRAIN_KEY)
+        .remove(-2);
+    if !ctx.is_function(-1) {
+        ctx.pop(1);
+        return Ok(0);
+    }
+    ctx.call(0)?;
+    let count = ctx.get_number(-1).unwrap_or(0.0);
+    ctx.pop(1);
+    Ok(count as usize)
+}
+
+/// Number of futures returned to scripts which did not complete yet
+pub(crate) fn pending_futures(ctx: &DukContext) -> usize {
+    match ctx.data() {
+        Ok(data) => data.get::<Tasks>().map_or(0, |list| list.tasks.len()),
+        Err(_) => 0,
+    }
+}
+
+/// Push a promise settled with the output of `future`
+pub(crate) fn push_future<F, T>(ctx: &DukContext, future: F) -> Result<()>
+where
+    F: 'static + Future<Output = Result<T>>,
+    T: 'static + ToDuktape,
+{
+    init(ctx)?;
+    let handle = handle::handle(ctx)?;
+
+    ctx.push_global_stash()
+        .get_prop_string(-1, DEFER_KEY)
+        .remove(-2);
+    ctx.call(0)?;
+    ctx.get_prop_string(-1, "resolve");
+    let resolve = unsafe { make_ref(ctx.inner) };
+    ctx.get_prop_string(-1, "reject");
+    let reject = unsafe { make_ref(ctx.inner) };
+    ctx.get_prop_string(-1, "promise").remove(-2);
+
+    let future = async move {
+        let value = future.await?;
+        Ok(Box::new(move |ctx: &DukContext| value.to_context(ctx)) as Settle)
+    };
+
+    let list = ctx
+        .data_mut()?
+        .entry::<Tasks>()
+        .or_insert_with(TaskList::default);
+    let id = list.next;
+    list.next += 1;
+    list.tasks.insert(
+        id,
+        Task {
+            future: Box::pin(future),
+            resolve,
+            reject,
+        },
+    );
+
+    // The first poll happens once the calling script returned
+    handle.execute(move |ctx| {
+        let _ = poll_task(ctx, id);
+    })
+}
+
+fn poll_task(ctx: &DukContext, id: u64) -> Result<()> {
+    // Taken out while polling, the future may use the context data
+    let mut task = match ctx.data_mut()?.get_mut::<Tasks>() {
+        Some(list) => match list.tasks.remove(&id) {
+            Some(task) => task,
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    let waker = Waker::from(Arc::new(TaskWaker {
+        handle: handle::handle(ctx)?,
+        id,
+    }));
+    let output = match task
+        .future
+        .as_mut()
+        .poll(&mut TaskContext::from_waker(&waker))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => {
+            if let Some(list) = ctx.data_mut()?.get_mut::<Tasks>() {
+                list.tasks.insert(id, task);
+            }
+            return Ok(());
+        }
+    };
+
+    let top = ctx.top();
+    let pushed = output.and_then(|settle| {
+        unsafe { push_ref(ctx.inner, task.resolve) };
+        settle(ctx)
+    });
+    if let Err(e) = pushed {
+        unsafe {
+            duk_set_top(ctx.inner, top);
+            push_ref(ctx.inner, task.reject);
+        }
+        ctx.push_error(e);
+    }
+    unsafe {
+        unref(ctx.inner, task.resolve);
+        unref(ctx.inner, task.reject);
+    }
+
+    let ret = ctx.call(1).map(|ctx| ctx.pop(1));
+    run_microtasks(ctx)?;
+    ret.map(|_| ())
+}
+
+/// Outcome of an awaited promise, set by its reactions
+#[derive(Default)]
+struct Settled {
+    result: Option<Result<u32>>,
+    waker: Option<Waker>,
+}
+
+impl Settled {
+    fn settle(&mut self, result: Result<u32>) {
+        self.result = Some(result);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A promise, or any value, awaited from Rust
+///
+/// Resolves once the reactions of the promise ran, see
+/// `DukContext::run_microtasks`.
+pub struct JsFuture<'a> {
+    ctx: &'a DukContext,
+    state: Rc<RefCell<Settled>>,
+}
+
+impl<'a> Future for JsFuture<'a> {
+    type Output = Result<Ref<'a>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<Self::Output> {
+        let mut state = self.state.borrow_mut();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result.map(|refer| Ref::from_raw(self.ctx, refer))),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a> Drop for JsFuture<'a> {
+    fn drop(&mut self) {
+        if let Some(Ok(refer)) = self.state.borrow_mut().result.take() {
+            unsafe { unref(self.ctx.inner, refer) };
+        }
+    }
+}
+
+/// Await the value at `idx` the way `await` does in scripts
+pub(crate) fn to_future(ctx: &DukContext, idx: i32) -> Result<JsFuture<'_>> {
+    let idx = ctx.normalize_index(idx);
+    init(ctx)?;
+
+    let state = Rc::new(RefCell::new(Settled::default()));
+    // Only the future owns the state, nothing is pinned for it once dropped
+    let fulfilled = Rc::downgrade(&state);
+    let rejected = Rc::downgrade(&state);
+
+    ctx.push_global_stash()
+        .get_prop_string(-1, AWAIT_KEY)
+        .remove(-2);
+    ctx.dup(idx)
+        .push_function((1, move |ctx: &DukContext| {
+            if let Some(state) = Weak::upgrade(&fulfilled) {
+                ctx.dup(0);
+                let refer = unsafe { make_ref(ctx.inner) };
+                state.borrow_mut().settle(Ok(refer));
+            }
+            Ok(0)
+        }))
+        .push_function((1, move |ctx: &DukContext| {
+            if let Some(state) = Weak::upgrade(&rejected) {
+                // Pins the reason in the exception, without taking the
+                // errors of the heap limits meant for the running call
+                let err = ctx.describe_error(0).into_error();
+                state.borrow_mut().settle(Err(err));
+            }
+            Ok(0)
+        }));
+    ctx.call(3)?.pop(1);
+
+    Ok(JsFuture { ctx, state })
+}
+
+struct Flag(AtomicBool, handle::ContextHandle);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+        // Unblocks `wait_jobs` when woken from another thread
+        let _ = self.1.execute(|_| {});
+    }
+}
+
+/// Poll `future` to completion, running jobs and promise reactions meanwhile.
+/// Nothing else is run, so `future` must not depend on e.g. timers.
+pub(crate) fn block_on<F: Future>(ctx: &DukContext, future: F) -> Result<F::Output> {
+    let mut future = Box::pin(future);
+    let flag = Arc::new(Flag(AtomicBool::new(true), handle::handle(ctx)?));
+    let waker = Waker::from(flag.clone());
+
+    loop {
+        if flag.0.swap(false, Ordering::SeqCst) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut TaskContext::from_waker(&waker))
+            {
+                return Ok(output);
+            }
+        }
+
+        let ran = handle::run_jobs(ctx)? + run_microtasks(ctx)?;
+        if ran == 0 && !flag.0.load(Ordering::SeqCst) {
+            handle::wait_jobs(ctx, None)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::callable::typed;
+    use crate::error::{Error, ErrorKind};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Completes from another thread, like network or file io
+    struct Later {
+        state: Arc<Mutex<(Option<Result<i32>>, Option<Waker>)>>,
+    }
+
+    impl Later {
+        fn new(value: std::result::Result<i32, &'static str>) -> Later {
+            let state = Arc::new(Mutex::new((None, None::<Waker>)));
+            let shared = state.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                let mut state = shared.lock().unwrap();
+                state.0 = Some(value.map_err(Error::range_err));
+                if let Some(waker) = state.1.take() {
+                    waker.wake();
+                }
+            });
+            Later { state }
+        }
+    }
+
+    impl Future for Later {
+        type Output = Result<i32>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<Result<i32>> {
+            let mut state = self.state.lock().unwrap();
+            match state.0.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    state.1 = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn futures_to_promises() {
+        let ctx = DukContext::new().unwrap();
+        ctx.push_global_object()
+            .push_function(typed(|x: i32| Ok(Async(Later::new(Ok(x * 2))))))
+            .put_prop_string(-2, "double")
+            .push_function(typed(|| Ok(Async(Later::new(Err("nope"))))))
+            .put_prop_string(-2, "fail")
+            .pop(1);
+
+        ctx.eval("double(21).then(function (v) { return v + 1; })")
+            .unwrap();
+        let value = ctx.to_future(-1).unwrap();
+        let value: i32 = ctx.block_on(value).unwrap().unwrap().get().unwrap();
+        assert_eq!(value, 43);
+        ctx.pop(1);
+
+        ctx.eval("fail()['catch'](function (e) { return e instanceof RangeError && e.message; })")
+            .unwrap();
+        let message = ctx.to_future(-1).unwrap();
+        let message: String = ctx.block_on(message).unwrap().unwrap().get().unwrap();
+        assert_eq!(message, "nope");
+        ctx.pop(1);
+    }
+
+    #[test]
+    fn awaiting_promises() {
+        let ctx = DukContext::new().unwrap();
+        ctx.init_promise().unwrap();
+
+        ctx.eval("({ then: function (resolve) { resolve(5); } })")
+            .unwrap();
+        let thenable = ctx.to_future(-1).unwrap();
+        assert_eq!(
+            ctx.block_on(thenable)
+                .unwrap()
+                .unwrap()
+                .get::<i32>()
+                .unwrap(),
+            5
+        );
+        ctx.pop(1);
+
+        ctx.eval("Promise.reject(new TypeError('bad'))").unwrap();
+        let rejected = ctx.to_future(-1).unwrap();
+        let err = match ctx.block_on(rejected).unwrap() {
+            Err(e) => e,
+            Ok(_) => panic!("promise should reject"),
+        };
+        match err.kind() {
+            ErrorKind::TypeError(m) => assert_eq!(m, "bad"),
+            k => panic!("unexpected error: {}", k),
+        }
+        ctx.pop(1);
+        // The reason is kept by the error, whatever is thrown afterwards
+        assert!(ctx.eval("throw new RangeError('other')").is_err());
+        let reason = err.exception().unwrap().value(&ctx).unwrap();
+        let message: String = reason
+            .get::<crate::types::Object>()
+            .unwrap()
+            .get("message")
+            .unwrap();
+        assert_eq!(message, "bad");
+
+        let order: String = ctx
+            .eval(
+                r#"
+                var order = [];
+                Promise.resolve(1).then(function () { order.push('then'); });
+                order.push('sync');
+                order
+                "#,
+            )
+            .unwrap()
+            .pop(1)
+            .run_microtasks()
+            .and_then(|_| ctx.eval("order.join()"))
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert_eq!(order, "sync,then");
+        assert_eq!(ctx.top(), 0);
+    }
+
+    #[test]
+    fn dropped_futures() {
+        let ctx = DukContext::new().unwrap();
+        ctx.init_promise().unwrap();
+
+        ctx.eval(
+            r#"
+            var finalized = false;
+            var value = {};
+            Duktape.fin(value, function () { finalized = true; });
+            var settle;
+            new Promise(function (resolve) { settle = resolve; })
+            "#,
+        )
+        .unwrap();
+        let future = ctx.to_future(-1).unwrap();
+        drop(future);
+        ctx.pop(1);
+
+        // Settled after the future is gone, the value is not pinned for it
+        ctx.eval("settle(value); value = null; settle = null;")
+            .unwrap()
+            .pop(1);
+        ctx.run_microtasks().unwrap();
+        ctx.eval("Duktape.gc()").unwrap().pop(1);
+        let finalized: bool = ctx.eval("finalized").unwrap().getp().unwrap();
+        assert!(finalized);
+    }
+}
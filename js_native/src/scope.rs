@@ -0,0 +1,358 @@
+//!
+//! Scoped stack frames
+//!
+//! A `Scope` owns the value stack slots pushed through it and hands out
+//! `StackValue` handles to them instead of raw indices. The stack is
+//! truncated back to where the scope started once it ends, so values can
+//! not leak past it. Debug builds panic when the scope finds slots it did
+//! not push, or misses some it did, which points at unbalanced raw stack
+//! manipulation inside the scope.
+//!
+
+use super::ctx::{DukContext, Idx};
+use super::error::{ErrorKind, Result};
+use super::types::{FromDuktape, ToDuktape, Type};
+use dukbind::*;
+use std::cell::Cell;
+use std::fmt;
+use std::thread;
+
+pub struct Scope<'c> {
+    ctx: &'c DukContext,
+    base: Idx,
+    top: Cell<Idx>,
+}
+
+impl<'c> Scope<'c> {
+    pub(crate) fn new(ctx: &'c DukContext) -> Scope<'c> {
+        let base = ctx.top();
+        Scope {
+            ctx,
+            base,
+            top: Cell::new(base),
+        }
+    }
+
+    /// The context of the scope, for raw stack access. Anything pushed
+    /// through it must be popped again, or adopted with `adopt`.
+    pub fn ctx(&self) -> &'c DukContext {
+        self.ctx
+    }
+
+    /// Number of values owned by the scope
+    pub fn len(&self) -> usize {
+        (self.top.get() - self.base) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A value below the scope, e.g. an argument of the running native function
+    pub fn arg(&self, index: Idx) -> Result<StackValue<'_>> {
+        let idx = self.ctx.normalize_index(index);
+        if idx < 0 || idx >= self.base {
+            return Err(ErrorKind::ReferenceError(format!("invalid index: {}", index)).into());
+        }
+        Ok(StackValue { scope: self, idx })
+    }
+
+    pub fn push<T: ToDuktape>(&self, value: T) -> Result<StackValue<'_>> {
+        self.check();
+        self.settle(value.to_context(self.ctx).map(|_| ()))
+    }
+
+    pub fn push_undefined(&self) -> StackValue<'_> {
+        self.check();
+        self.ctx.push_undefined();
+        self.take()
+    }
+
+    pub fn push_object(&self) -> StackValue<'_> {
+        self.check();
+        self.ctx.push_object();
+        self.take()
+    }
+
+    pub fn push_array(&self) -> StackValue<'_> {
+        self.check();
+        self.ctx.push_array();
+        self.take()
+    }
+
+    pub fn global(&self) -> StackValue<'_> {
+        self.check();
+        self.ctx.push_global_object();
+        self.take()
+    }
+
+    /// Evaluate a script, its result is owned by the scope
+    pub fn eval<T: AsRef<[u8]>>(&self, script: T) -> Result<StackValue<'_>> {
+        self.check();
+        self.settle(self.ctx.eval(script).map(|_| ()))
+    }
+
+    /// Take ownership of the value on top of the stack, pushed through `ctx`
+    pub fn adopt(&self) -> StackValue<'_> {
+        assert!(
+            self.ctx.top() > self.top.get(),
+            "no value to adopt in the scope"
+        );
+        self.top.set(self.top.get() + 1);
+        self.check();
+        StackValue {
+            scope: self,
+            idx: self.top.get() - 1,
+        }
+    }
+
+    /// Run `f` in a nested scope. The outer scope is borrowed until it
+    /// returns, so none of its values can be used meanwhile.
+    ///
+    /// ```compile_fail
+    /// # use js_native::DukContext;
+    /// let ctx = DukContext::new().unwrap();
+    /// ctx.scope(|s| {
+    ///     let outer = s.push_object();
+    ///     s.scope(|_inner| outer.set_prop("x", 1).unwrap());
+    /// });
+    /// ```
+    pub fn scope<R, F: FnOnce(&mut Scope) -> R>(&mut self, f: F) -> R {
+        self.check();
+        f(&mut Scope::new(self.ctx))
+    }
+
+    /// Panics in debug builds if the stack top is not where the scope left it
+    fn check(&self) {
+        let top = self.ctx.top();
+        let expected = self.top.get();
+        debug_assert!(
+            top <= expected,
+            "{} stack slot(s) leaked in the scope",
+            top - expected
+        );
+        debug_assert!(
+            top >= expected,
+            "{} stack slot(s) of the scope popped behind its back",
+            expected - top
+        );
+    }
+
+    /// Take the value the last operation pushed
+    fn take(&self) -> StackValue<'_> {
+        let idx = self.top.get();
+        self.top.set(idx + 1);
+        StackValue { scope: self, idx }
+    }
+
+    /// Take the single value pushed by an operation, or drop whatever it
+    /// left behind when it failed
+    fn settle(&self, result: Result<()>) -> Result<StackValue<'_>> {
+        let expected = self.top.get();
+        match result {
+            Ok(()) => {
+                debug_assert_eq!(
+                    self.ctx.top(),
+                    expected + 1,
+                    "operation pushed an unexpected number of values"
+                );
+                Ok(self.take())
+            }
+            Err(e) => {
+                if self.ctx.top() > expected {
+                    unsafe { duk_set_top(self.ctx.inner, expected) };
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'c> Drop for Scope<'c> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            self.check();
+        }
+        let top = self.ctx.top();
+        if top > self.base {
+            unsafe { duk_set_top(self.ctx.inner, self.base) };
+        }
+    }
+}
+
+impl<'c> fmt::Debug for Scope<'c> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scope")
+            .field("base", &self.base)
+            .field("top", &self.top.get())
+            .finish()
+    }
+}
+
+/// A value on the stack, owned by a `Scope`
+#[derive(Clone, Copy)]
+pub struct StackValue<'s> {
+    scope: &'s Scope<'s>,
+    idx: Idx,
+}
+
+impl<'s> StackValue<'s> {
+    /// Absolute stack index of the value
+    pub fn index(&self) -> Idx {
+        self.idx
+    }
+
+    pub fn get_type(&self) -> Type {
+        self.scope.ctx.get_type(self.idx)
+    }
+
+    pub fn is(&self, t: Type) -> bool {
+        self.get_type() == t
+    }
+
+    pub fn get<T: FromDuktape<'s>>(&self) -> Result<T> {
+        self.scope.ctx.get(self.idx)
+    }
+
+    /// Push a copy of the value, owned by the same scope
+    pub fn dup(&self) -> StackValue<'s> {
+        self.scope.check();
+        self.scope.ctx.dup(self.idx);
+        self.scope.take()
+    }
+
+    pub fn get_prop(&self, key: &str) -> Result<StackValue<'s>> {
+        self.scope.check();
+        let ctx = self.scope.ctx;
+        ctx.push_string(key);
+        self.scope.settle(ctx.duk_get_prop(self.idx).map(|_| ()))
+    }
+
+    pub fn set_prop<T: ToDuktape>(&self, key: &str, value: T) -> Result<()> {
+        self.scope.check();
+        let ctx = self.scope.ctx;
+        let top = ctx.top();
+        let ret = ctx
+            .push_string(key)
+            .push(value)
+            .and_then(|ctx| ctx.duk_put_prop(self.idx).map(|_| ()));
+        if ret.is_err() && ctx.top() > top {
+            unsafe { duk_set_top(ctx.inner, top) };
+        }
+        ret
+    }
+
+    /// Call the value as a function with `this` undefined
+    pub fn call(&self, args: &[StackValue<'s>]) -> Result<StackValue<'s>> {
+        self.scope.check();
+        let ctx = self.scope.ctx;
+        ctx.dup(self.idx);
+        for arg in args {
+            ctx.dup(arg.idx);
+        }
+        self.scope.settle(ctx.call(args.len() as i32).map(|_| ()))
+    }
+
+    /// Call the method `key` of the value
+    pub fn call_method(&self, key: &str, args: &[StackValue<'s>]) -> Result<StackValue<'s>> {
+        let func = self.get_prop(key)?;
+        let ctx = self.scope.ctx;
+        // Replaces `func` in place, so the scope keeps a single slot
+        ctx.dup(self.idx);
+        for arg in args {
+            ctx.dup(arg.idx);
+        }
+        let ret = ctx.call_method(args.len() as i32).map(|_| ());
+        self.scope.top.set(func.idx);
+        self.scope.settle(ret)
+    }
+}
+
+impl<'s> fmt::Debug for StackValue<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StackValue")
+            .field("index", &self.idx)
+            .field("type", &self.get_type())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ctx::DukContext;
+    use crate::error::Result;
+    use crate::types::Type;
+
+    #[test]
+    fn scoped_values() {
+        let ctx = DukContext::new().unwrap();
+        ctx.push_int(1);
+
+        let sum: f64 = ctx.scope(|s| {
+            let add = s.eval("(function (a, b) { return a + b; })").unwrap();
+            assert!(add.is(Type::Function));
+            let a = s.push(2).unwrap();
+            let b = s.arg(0).unwrap();
+            let out = add.call(&[a, b]).unwrap();
+            assert_eq!(s.len(), 3);
+
+            let obj = s.push_object();
+            obj.set_prop("x", "y").unwrap();
+            let x: String = obj.get_prop("x").unwrap().get().unwrap();
+            assert_eq!(x, "y");
+            let sum = out.get().unwrap();
+
+            let nested = s.scope(|inner| {
+                let value = inner.push("nested").unwrap();
+                value.get_prop("length").unwrap().get::<f64>().unwrap()
+            });
+            assert_eq!(nested, 6.0);
+            assert_eq!(s.len(), 5);
+
+            let upper = s
+                .push("abc")
+                .unwrap()
+                .call_method("toUpperCase", &[])
+                .unwrap();
+            assert_eq!(upper.get::<String>().unwrap(), "ABC");
+
+            assert!(s.eval("throw new Error('x')").is_err());
+            sum
+        });
+
+        assert_eq!(sum, 3.0);
+        assert_eq!(ctx.top(), 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "leaked in the scope")]
+    fn leak() {
+        let ctx = DukContext::new().unwrap();
+        ctx.scope(|s| {
+            s.ctx().push_int(1);
+        });
+    }
+
+    #[test]
+    fn unbalanced_callable() {
+        let ctx = DukContext::builder().check_stack(true).build().unwrap();
+        ctx.push_global_object()
+            .push_function((1, |ctx: &DukContext| -> Result<i32> {
+                ctx.push_int(1).push_int(2);
+                Ok(1)
+            }))
+            .put_prop_string(-2, "leaky")
+            .push_function((1, |ctx: &DukContext| -> Result<i32> {
+                ctx.push_int(1);
+                Ok(1)
+            }))
+            .put_prop_string(-2, "fine")
+            .pop(1);
+
+        assert!(ctx.eval("fine(0)").is_ok());
+        ctx.pop(1);
+        let err = ctx.eval("leaky(0)").unwrap_err().to_string();
+        assert!(err.contains("expected top 2, found 3"), "{}", err);
+    }
+}
@@ -0,0 +1,417 @@
+//!
+//! Source map support
+//!
+//! Source maps registered with `DukContext::register_source_map` are keyed
+//! by the file name a script was compiled with, e.g. through
+//! `compile_string_filename`. Once a map is registered, errors created by
+//! scripts of that file have their `stack`, `fileName` and `lineNumber`
+//! rewritten to the original sources, both as seen by scripts and in the
+//! `JsException` of the Rust error.
+//!
+//! Duktape only records line numbers in its stack traces, so frames map to
+//! the first mapping of their generated line.
+//!
+
+use super::ctx::DukContext;
+use super::error::{ErrorKind, Result};
+use super::privates;
+use dukbind::*;
+use std::collections::HashMap;
+use typemap::Key;
+
+/// Installs the `Duktape.errCreate` hook, chaining to any previous one
+static HOOK: &'static str = r#"(function (rewrite) {
+    var previous = Duktape.errCreate;
+    Duktape.errCreate = function (e) {
+        if (typeof previous === 'function') e = previous(e);
+        if (!(e instanceof Error)) return e;
+        try {
+            var mapped = rewrite(e.stack, e.fileName, e.lineNumber);
+            if (mapped) {
+                var keys = ['stack', 'fileName', 'lineNumber'];
+                for (var i = 0; i < keys.length; i++) {
+                    Object.defineProperty(e, keys[i], {
+                        value: mapped[i],
+                        writable: true,
+                        configurable: true
+                    });
+                }
+            }
+        } catch (x) {}
+        return e;
+    };
+})"#;
+
+/// A position in an original source, with 1 based line and column
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub source: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    column: u32,
+    source: u32,
+    line: u32,
+    original_column: u32,
+}
+
+/// A decoded source map, version 3
+#[derive(Debug, Clone)]
+pub struct SourceMap {
+    sources: Vec<String>,
+    /// Mappings of every generated line, sorted by column
+    lines: Vec<Vec<Mapping>>,
+}
+
+impl SourceMap {
+    /// Parse the JSON of a source map. Index maps are not supported.
+    pub fn parse<T: AsRef<str>>(ctx: &DukContext, json: T) -> Result<SourceMap> {
+        ctx.push_string(json.as_ref());
+        let ret = unsafe {
+            privates::safe_call(ctx.inner, 1, 1, |ctx| {
+                duk_json_decode(ctx, -1);
+                1
+            })
+        };
+        if ret != DUK_EXEC_SUCCESS as i32 {
+            return Err(ctx.take_error());
+        }
+
+        let map = (|| {
+            ctx.get_prop_string(-1, "version");
+            let version = ctx.get::<Option<f64>>(-1);
+            ctx.pop(1);
+            if version? != Some(3.0) {
+                bail!(ErrorKind::Error(
+                    "unsupported source map version".to_owned()
+                ));
+            }
+
+            ctx.get_prop_string(-1, "sourceRoot");
+            let root = ctx.get::<Option<String>>(-1);
+            ctx.get_prop_string(-2, "sources");
+            let sources = ctx.get::<Vec<Option<String>>>(-1);
+            ctx.get_prop_string(-3, "mappings");
+            let mappings = ctx.get::<String>(-1);
+            ctx.pop(3);
+
+            let root = root?.unwrap_or_default();
+            let sources = sources?
+                .into_iter()
+                .map(|s| {
+                    let s = s.unwrap_or_default();
+                    if root.is_empty() || root.ends_with('/') {
+                        format!("{}{}", root, s)
+                    } else {
+                        format!("{}/{}", root, s)
+                    }
+                })
+                .collect();
+
+            SourceMap::from_mappings(sources, &mappings?)
+        })();
+        ctx.pop(1);
+        map
+    }
+
+    /// Build a source map from its sources and its VLQ encoded mappings
+    pub fn from_mappings(sources: Vec<String>, mappings: &str) -> Result<SourceMap> {
+        let mut lines = Vec::new();
+        // Every field but the generated column is relative across lines
+        let (mut source, mut line, mut original_column) = (0i64, 0i64, 0i64);
+
+        for encoded in mappings.split(';') {
+            let mut segments = Vec::new();
+            let mut column = 0i64;
+
+            for segment in encoded.split(',').filter(|s| !s.is_empty()) {
+                let fields = decode_vlq(segment)?;
+                column += fields[0];
+                if fields.len() < 4 {
+                    continue;
+                }
+                source += fields[1];
+                line += fields[2];
+                original_column += fields[3];
+
+                if column < 0 || line < 0 || original_column < 0 {
+                    bail!(ErrorKind::Error("negative source map position".to_owned()));
+                }
+                if source < 0 || source as usize >= sources.len() {
+                    bail!(ErrorKind::Error(format!(
+                        "source map refers to unknown source {}",
+                        source
+                    )));
+                }
+                segments.push(Mapping {
+                    column: column as u32,
+                    source: source as u32,
+                    line: line as u32,
+                    original_column: original_column as u32,
+                });
+            }
+
+            segments.sort_by_key(|m| m.column);
+            lines.push(segments);
+        }
+
+        Ok(SourceMap { sources, lines })
+    }
+
+    /// Original location of a 1 based generated line and 0 based column.
+    /// Columns before the first mapping of the line use that mapping.
+    pub fn lookup(&self, line: u32, column: u32) -> Option<Location> {
+        let segments = self.lines.get((line as usize).checked_sub(1)?)?;
+        let mapping = segments
+            .iter()
+            .rev()
+            .find(|m| m.column <= column)
+            .or_else(|| segments.first())?;
+
+        Some(Location {
+            source: self.sources[mapping.source as usize].clone(),
+            line: mapping.line + 1,
+            column: mapping.original_column + 1,
+        })
+    }
+
+    /// Rewrite the frames of a Duktape stack trace referring to `file`
+    fn rewrite_stack(&self, file: &str, stack: &str) -> Option<String> {
+        let mut changed = false;
+        let lines: Vec<String> = stack
+            .lines()
+            .map(|l| match self.rewrite_frame(file, l) {
+                Some(frame) => {
+                    changed = true;
+                    frame
+                }
+                None => l.to_owned(),
+            })
+            .collect();
+
+        if changed {
+            Some(lines.join("\n"))
+        } else {
+            None
+        }
+    }
+
+    /// Frames look like `    at name (file:line) strict`
+    fn rewrite_frame(&self, file: &str, frame: &str) -> Option<String> {
+        if !frame.trim_start().starts_with("at ") {
+            return None;
+        }
+        let open = frame.rfind('(')?;
+        let close = open + frame[open..].find(')')?;
+        let (name, line) = split_location(&frame[open + 1..close])?;
+        if name != file {
+            return None;
+        }
+
+        let location = self.lookup(line, 0)?;
+        Some(format!(
+            "{}({}:{}:{}){}",
+            &frame[..open],
+            location.source,
+            location.line,
+            location.column,
+            &frame[close + 1..]
+        ))
+    }
+}
+
+/// Split `file:line` on its last colon
+fn split_location(location: &str) -> Option<(&str, u32)> {
+    let colon = location.rfind(':')?;
+    let line = location[colon + 1..].parse().ok()?;
+    Some((&location[..colon], line))
+}
+
+fn decode_vlq(segment: &str) -> Result<Vec<i64>> {
+    let mut fields = Vec::new();
+    let (mut value, mut shift) = (0i64, 0);
+
+    for c in segment.bytes() {
+        let digit = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => bail!(ErrorKind::Error(format!(
+                "invalid character in source map mappings: {:?}",
+                c as char
+            ))),
+        } as i64;
+        if shift > 32 {
+            bail!(ErrorKind::Error("source map value overflow".to_owned()));
+        }
+
+        value += (digit & 31) << shift;
+        if digit & 32 != 0 {
+            shift += 5;
+            continue;
+        }
+
+        fields.push(if value & 1 == 1 {
+            -(value >> 1)
+        } else {
+            value >> 1
+        });
+        value = 0;
+        shift = 0;
+    }
+
+    if shift != 0 || !(fields.len() == 1 || fields.len() == 4 || fields.len() == 5) {
+        bail!(ErrorKind::Error(format!(
+            "invalid source map segment {:?}",
+            segment
+        )));
+    }
+    Ok(fields)
+}
+
+struct SourceMaps;
+
+impl Key for SourceMaps {
+    type Value = HashMap<String, SourceMap>;
+}
+
+pub(crate) fn register(ctx: &DukContext, file_name: &str, map: SourceMap) -> Result<()> {
+    let installed = ctx.data()?.contains::<SourceMaps>();
+    ctx.data_mut()?
+        .entry::<SourceMaps>()
+        .or_insert_with(HashMap::new)
+        .insert(file_name.to_owned(), map);
+
+    if !installed {
+        ctx.eval(HOOK)?;
+        ctx.push_function((3, rewrite_fn));
+        ctx.call(1)?.pop(1);
+    }
+
+    Ok(())
+}
+
+/// `rewrite(stack, fileName, lineNumber)`, returning the rewritten values
+/// or undefined if the error does not come from a mapped file
+fn rewrite_fn(ctx: &DukContext) -> Result<i32> {
+    let stack = ctx.get::<Option<String>>(0).unwrap_or(None);
+    let file_name = ctx.get::<Option<String>>(1).unwrap_or(None);
+    let line_number = ctx.get::<Option<f64>>(2).unwrap_or(None);
+
+    let maps = match ctx.data()?.get::<SourceMaps>() {
+        Some(maps) => maps,
+        None => return Ok(0),
+    };
+
+    let mut changed = false;
+    let stack = stack.map(|stack| {
+        let mut stack = stack;
+        for (file, map) in maps {
+            if let Some(rewritten) = map.rewrite_stack(file, &stack) {
+                stack = rewritten;
+                changed = true;
+            }
+        }
+        stack
+    });
+
+    let location = match (&file_name, line_number) {
+        (Some(file), Some(line)) => maps.get(file).and_then(|map| map.lookup(line as u32, 0)),
+        _ => None,
+    };
+    if !changed && location.is_none() {
+        return Ok(0);
+    }
+
+    ctx.push_array();
+    match stack {
+        Some(stack) => ctx.push_string(stack),
+        None => ctx.push_undefined(),
+    };
+    ctx.put_prop_index(-2, 0);
+    match location {
+        Some(location) => {
+            ctx.push_string(location.source)
+                .put_prop_index(-2, 1)
+                .push_uint(location.line)
+                .put_prop_index(-2, 2);
+        }
+        None => {
+            ctx.push(file_name)?
+                .put_prop_index(-2, 1)
+                .push(line_number)?
+                .put_prop_index(-2, 2);
+        }
+    }
+    Ok(1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ctx::Compile;
+    use crate::error::Error;
+
+    static MAP: &'static str = r#"{
+        "version": 3,
+        "file": "bundle.js",
+        "sourceRoot": "src",
+        "sources": ["a.js", "b.js"],
+        "names": [],
+        "mappings": "AAAA;EAIE;ACJF"
+    }"#;
+
+    static BUNDLE: &'static str = "function a() {\n  throw new Error('boom');\n}";
+
+    #[test]
+    fn decode_mappings() {
+        let ctx = DukContext::new().unwrap();
+        let map = SourceMap::parse(&ctx, MAP).unwrap();
+
+        let location = |source: &str, line, column| Location {
+            source: source.to_owned(),
+            line,
+            column,
+        };
+        assert_eq!(map.lookup(1, 0), Some(location("src/a.js", 1, 1)));
+        assert_eq!(map.lookup(2, 0), Some(location("src/a.js", 5, 3)));
+        assert_eq!(map.lookup(2, 10), Some(location("src/a.js", 5, 3)));
+        assert_eq!(map.lookup(3, 0), Some(location("src/b.js", 1, 1)));
+        assert_eq!(map.lookup(4, 0), None);
+        assert_eq!(ctx.top(), 0);
+
+        assert!(SourceMap::parse(&ctx, "{").is_err());
+        assert!(SourceMap::from_mappings(vec![], "AAAA").is_err());
+        assert!(SourceMap::from_mappings(vec!["a.js".to_owned()], "A!AA").is_err());
+        assert_eq!(ctx.top(), 0);
+    }
+
+    #[test]
+    fn mapped_stack_traces() {
+        let ctx = DukContext::new().unwrap();
+        ctx.register_source_map("bundle.js", MAP).unwrap();
+        ctx.compile_string_filename(BUNDLE, "bundle.js", Compile::empty())
+            .unwrap();
+        ctx.call(0).unwrap().pop(1);
+
+        let seen: String = ctx
+            .eval("try { a(); } catch (e) { e.fileName + ':' + e.lineNumber + '\\n' + e.stack }")
+            .unwrap()
+            .getp()
+            .unwrap();
+        assert!(seen.starts_with("src/a.js:5\n"), "{}", seen);
+        assert!(seen.contains("(src/a.js:5:3)"), "{}", seen);
+        assert!(!seen.contains("bundle.js"), "{}", seen);
+
+        let err: Error = ctx.eval("a()").unwrap_err();
+        let exception = err.exception().unwrap();
+        assert_eq!(exception.file_name.as_ref().unwrap(), "src/a.js");
+        assert_eq!(exception.line_number, Some(5));
+        assert!(exception.stack.as_ref().unwrap().contains("(src/a.js:5:3)"));
+    }
+}
@@ -1,11 +1,11 @@
-#[cfg(feature = "value")]
+use super::super::derive::context;
 use super::super::Enumerate;
 use super::super::{
     ctx::{DukContext, Idx},
     error::{ErrorKind, Result},
 };
-#[cfg(feature = "value")]
 use super::Type;
+use std::collections::{BTreeMap, HashMap};
 #[cfg(feature = "value")]
 use value::{Map, Number, Value};
 
@@ -18,7 +18,10 @@ macro_rules! impl_for_der {
         impl<'de> FromDuktape<'de> for $T {
             fn from_context(ctx: &'de DukContext, index: Idx) -> Result<Self> {
                 if !ctx.$check(index) {
-                    bail!(ErrorKind::TypeError("".to_string()));
+                    bail!(ErrorKind::TypeError(format!(
+                        "expected number, got: {:?}",
+                        ctx.get_type(index)
+                    )));
                 }
                 let ret = ctx.$func(index)?;
                 Ok(ret as $T)
@@ -35,6 +38,10 @@ impl_for_der!(usize, get_number, is_number);
 impl_for_der!(u8, get_uint, is_number);
 impl_for_der!(u16, get_uint, is_number);
 impl_for_der!(u32, get_uint, is_number);
+impl_for_der!(i64, get_number, is_number);
+impl_for_der!(u64, get_number, is_number);
+impl_for_der!(f32, get_number, is_number);
+impl_for_der!(f64, get_number, is_number);
 
 impl<'de> FromDuktape<'de> for bool {
     fn from_context(ctx: &'de DukContext, index: Idx) -> Result<Self> {
@@ -48,6 +55,16 @@ impl<'de> FromDuktape<'de> for () {
     }
 }
 
+/// `null` and `undefined` are `None`
+impl<'de, T: FromDuktape<'de>> FromDuktape<'de> for Option<T> {
+    fn from_context(ctx: &'de DukContext, index: Idx) -> Result<Self> {
+        if ctx.is_null(index) || ctx.is_undefined(index) {
+            return Ok(None);
+        }
+        T::from_context(ctx, index).map(Some)
+    }
+}
+
 impl<'de> FromDuktape<'de> for String {
     fn from_context(ctx: &'de DukContext, index: Idx) -> Result<Self> {
         if !ctx.is_string(index) {
@@ -85,6 +102,63 @@ impl<'de> FromDuktape<'de> for &'de [u8] {
     }
 }
 
+impl<'de, T: FromDuktape<'de>> FromDuktape<'de> for Vec<T> {
+    fn from_context(ctx: &'de DukContext, index: Idx) -> Result<Self> {
+        if !ctx.is_array(index) {
+            bail!(ErrorKind::TypeError(format!(
+                "expected array, got: {:?}",
+                ctx.get_type(index)
+            )));
+        }
+        let index = ctx.normalize_index(index);
+        let len = ctx.get_length(index);
+        let mut ret = Vec::with_capacity(len);
+        for i in 0..len {
+            ctx.get_prop_index(index, i as u32);
+            let value = T::from_context(ctx, -1);
+            ctx.pop(1);
+            ret.push(value.map_err(|e| context(e, &format!("[{}]", i)))?);
+        }
+        Ok(ret)
+    }
+}
+
+macro_rules! impl_for_map {
+    ($T:ident) => {
+        impl<'de, T: FromDuktape<'de>> FromDuktape<'de> for $T<String, T> {
+            fn from_context(ctx: &'de DukContext, index: Idx) -> Result<Self> {
+                if ctx.get_type(index) != Type::Object {
+                    bail!(ErrorKind::TypeError(format!(
+                        "expected object, got: {:?}",
+                        ctx.get_type(index)
+                    )));
+                }
+                let mut ret = $T::new();
+                ctx.enumerator(index, Enumerate::OWN_PROPERTIES_ONLY)?;
+                while ctx.next(-1, true)? {
+                    let key = ctx.get_string(-2)?.to_owned();
+                    let value = T::from_context(ctx, -1);
+                    ctx.pop(2);
+                    match value {
+                        Ok(v) => {
+                            ret.insert(key, v);
+                        }
+                        Err(e) => {
+                            ctx.pop(1);
+                            return Err(context(e, &key));
+                        }
+                    }
+                }
+                ctx.pop(1);
+                Ok(ret)
+            }
+        }
+    };
+}
+
+impl_for_map!(HashMap);
+impl_for_map!(BTreeMap);
+
 // #[cfg(feature = "value")]
 // impl<'de> FromDuktape<'de> for Number {
 //     fn from_context(ctx: &'de DukContext, index: Idx) -> Result<Self> {
@@ -116,7 +190,7 @@ impl<'de> FromDuktape<'de> for Value {
 
 #[cfg(feature = "value")]
 #[inline]
-fn pull_object(ctx: &Context, idx: Idx) -> Result<Value> {
+fn pull_object(ctx: &DukContext, idx: Idx) -> Result<Value> {
     ctx.enumerator(idx, Enumerate::OWN_PROPERTIES_ONLY)?;
     let mut map = Map::new();
     while ctx.next(-1, true)? {
@@ -132,7 +206,7 @@ fn pull_object(ctx: &Context, idx: Idx) -> Result<Value> {
 
 #[cfg(feature = "value")]
 #[inline]
-fn pull_array(ctx: &Context, idx: Idx) -> Result<Value> {
+fn pull_array(ctx: &DukContext, idx: Idx) -> Result<Value> {
     ctx.enumerator(idx, Enumerate::ARRAY_INDICES_ONLY)?;
     let mut map = Vec::new();
     while ctx.next(-1, true)? {
@@ -1,9 +1,54 @@
 use super::super::ctx::{DukContext, Idx};
-use super::super::error::Result;
+use super::super::error::{ErrorKind, Result};
+use super::super::privates;
 use super::argument_list::ArgumentList;
 use super::reference::Ref;
 use super::{FromDuktape, ToDuktape};
 use dukbind::*;
+use std::mem;
+
+/// Magic bytes starting every buffer produced by `Function::dump_bytecode`
+const BYTECODE_MAGIC: &[u8; 4] = b"DKRS";
+/// Magic, Duktape version, pointer width and endianness
+const BYTECODE_HEADER: usize = 10;
+
+fn bytecode_header() -> [u8; BYTECODE_HEADER] {
+    let mut header = [0; BYTECODE_HEADER];
+    header[..4].copy_from_slice(BYTECODE_MAGIC);
+    header[4..8].copy_from_slice(&(DUK_VERSION as u32).to_le_bytes());
+    header[8] = mem::size_of::<usize>() as u8;
+    header[9] = if cfg!(target_endian = "little") { 0 } else { 1 };
+    header
+}
+
+/// Check the header of a buffer produced by `Function::dump_bytecode`,
+/// returning the bytecode following it
+pub(crate) fn strip_bytecode_header(bytes: &[u8]) -> Result<&[u8]> {
+    if bytes.len() < BYTECODE_HEADER || &bytes[..4] != BYTECODE_MAGIC {
+        bail!(ErrorKind::IncompatibleBytecode(
+            "missing bytecode header".to_owned()
+        ));
+    }
+
+    let expected = bytecode_header();
+    let (header, bytecode) = bytes.split_at(BYTECODE_HEADER);
+    if header[4..8] != expected[4..8] {
+        let mut version = [0; 4];
+        version.copy_from_slice(&header[4..8]);
+        bail!(ErrorKind::IncompatibleBytecode(format!(
+            "dumped by Duktape {}, running {}",
+            u32::from_le_bytes(version),
+            DUK_VERSION
+        )));
+    }
+    if header[8..] != expected[8..] {
+        bail!(ErrorKind::IncompatibleBytecode(
+            "dumped on a different platform".to_owned()
+        ));
+    }
+
+    Ok(bytecode)
+}
 
 pub struct Function<'a> {
     pub(crate) refer: Ref<'a>,
@@ -24,6 +69,31 @@ impl<'a> Function<'a> {
         Ok(ret)
     }
 
+    /// Serialize the function to bytecode, loadable with `DukContext::load_bytecode`
+    ///
+    /// Only script functions can be dumped. The bytecode is prefixed with a
+    /// header identifying the Duktape build, and is not portable across
+    /// Duktape versions or platforms. Loading bytecode is not safe for
+    /// untrusted input.
+    pub fn dump_bytecode(&self) -> Result<Vec<u8>> {
+        let ctx = self.refer.ctx;
+        self.refer.push();
+        let ret = unsafe {
+            privates::safe_call(ctx.inner, 1, 1, |ctx| {
+                duk_dump_function(ctx);
+                1
+            })
+        };
+        if ret != DUK_EXEC_SUCCESS as i32 {
+            return Err(ctx.take_error());
+        }
+
+        let mut out = bytecode_header().to_vec();
+        out.extend_from_slice(ctx.get_bytes(-1)?);
+        ctx.pop(1);
+        Ok(out)
+    }
+
     pub fn set_name<T: AsRef<str>>(&mut self, name: T) -> &mut Self {
         self.refer.push();
         self.refer
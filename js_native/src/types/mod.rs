@@ -3,6 +3,7 @@ mod array;
 mod from_duktape;
 mod function;
 mod object;
+mod persistent;
 mod reference;
 mod to_duktape;
 
@@ -24,5 +25,6 @@ pub use self::array::*;
 pub use self::from_duktape::*;
 pub use self::function::*;
 pub use self::object::*;
+pub use self::persistent::*;
 pub use self::reference::*;
 pub use self::to_duktape::*;
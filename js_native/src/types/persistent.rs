@@ -0,0 +1,218 @@
+use super::super::ctx::{DukContext, Idx};
+use super::super::error::{ErrorKind, Result};
+use super::super::heap;
+use super::super::privates::{make_ref, push_ref, unref};
+use super::reference::Ref;
+use super::{FromDuktape, ToDuktape, Type};
+use dukbind::*;
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+/// A reference to a script value which does not borrow the context, so it
+/// can be kept in long-lived Rust structs, e.g. a callback handed to Rust.
+///
+/// The value is kept alive until the reference is dropped, or the heap
+/// destroyed. It can only be used with a context of the heap it was
+/// created in.
+///
+/// ```ignore
+/// let callback: PersistentRef = ctx.get(0)?;
+/// // later on
+/// let func: Function = callback.get(&ctx)?;
+/// ```
+pub struct PersistentRef {
+    root: Rc<Cell<*mut duk_context>>,
+    refer: u32,
+}
+
+impl PersistentRef {
+    /// Pin the value at `idx`
+    pub fn new(ctx: &DukContext, idx: Idx) -> Result<PersistentRef> {
+        let root = match unsafe { heap::get_heap(ctx.inner) } {
+            Some(heap) => heap.root.clone(),
+            None => bail!(ErrorKind::Error(
+                "context has no heap to pin values in".to_owned()
+            )),
+        };
+        if !ctx.is_valid_index(idx) {
+            bail!(ErrorKind::ReferenceError(format!("invalid index: {}", idx)));
+        }
+        unsafe { duk_dup(ctx.inner, idx) };
+        let refer = unsafe { make_ref(ctx.inner) };
+        Ok(PersistentRef { root, refer })
+    }
+
+    /// Whether `ctx` belongs to the heap the value lives in
+    pub fn is_same_heap(&self, ctx: &DukContext) -> bool {
+        match unsafe { heap::get_heap(ctx.inner) } {
+            Some(heap) => Rc::ptr_eq(&heap.root, &self.root),
+            None => false,
+        }
+    }
+
+    fn check(&self, ctx: &DukContext) -> Result<()> {
+        if !self.is_same_heap(ctx) {
+            bail!(ErrorKind::ReferenceError(
+                "persistent reference used with another heap".to_owned()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Push the value onto the stack of `ctx`
+    pub fn push(&self, ctx: &DukContext) -> Result<()> {
+        self.check(ctx)?;
+        unsafe { push_ref(ctx.inner, self.refer) };
+        Ok(())
+    }
+
+    /// Convert the value, e.g. into an `Object` or a `Function`
+    pub fn get<'a, T: FromDuktape<'a>>(&self, ctx: &'a DukContext) -> Result<T> {
+        self.push(ctx)?;
+        let ret = T::from_context(ctx, -1);
+        ctx.pop(1);
+        ret
+    }
+
+    /// Borrow the value again as a `Ref` of `ctx`
+    pub fn to_ref<'a>(&self, ctx: &'a DukContext) -> Result<Ref<'a>> {
+        self.get(ctx)
+    }
+
+    pub fn get_type(&self, ctx: &DukContext) -> Result<Type> {
+        self.push(ctx)?;
+        let ret = ctx.get_type(-1);
+        ctx.pop(1);
+        Ok(ret)
+    }
+}
+
+impl<'a> ToDuktape for &'a PersistentRef {
+    fn to_context(self, ctx: &DukContext) -> Result<()> {
+        self.push(ctx)
+    }
+}
+
+impl ToDuktape for PersistentRef {
+    fn to_context(self, ctx: &DukContext) -> Result<()> {
+        self.push(ctx)
+    }
+}
+
+impl<'a> FromDuktape<'a> for PersistentRef {
+    fn from_context(ctx: &'a DukContext, index: Idx) -> Result<Self> {
+        PersistentRef::new(ctx, index)
+    }
+}
+
+impl Clone for PersistentRef {
+    fn clone(&self) -> Self {
+        let root = self.root.get();
+        let refer = if root.is_null() {
+            0
+        } else {
+            unsafe {
+                push_ref(root, self.refer);
+                make_ref(root)
+            }
+        };
+        PersistentRef {
+            root: self.root.clone(),
+            refer,
+        }
+    }
+}
+
+impl fmt::Debug for PersistentRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PersistentRef")
+            .field("refer", &self.refer)
+            .field("alive", &!self.root.get().is_null())
+            .finish()
+    }
+}
+
+impl Drop for PersistentRef {
+    fn drop(&mut self) {
+        // The refs table went away with the heap
+        let root = self.root.get();
+        if !root.is_null() {
+            unsafe { unref(root, self.refer) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PersistentRef;
+    use crate::ctx::DukContext;
+    use crate::types::{Function, Object, Type};
+
+    struct Handler {
+        callback: PersistentRef,
+    }
+
+    #[test]
+    fn outlives_borrow() {
+        let ctx = DukContext::new().unwrap();
+        let handler = {
+            ctx.eval(
+                r#"
+                var finalized = false;
+                (function () {
+                    var f = function (a, b) { return a * b; };
+                    Duktape.fin(f, function () { finalized = true; });
+                    return f;
+                })()
+                "#,
+            )
+            .unwrap();
+            let callback: PersistentRef = ctx.getp().unwrap();
+            Handler { callback }
+        };
+        assert_eq!(ctx.top(), 0);
+
+        ctx.eval("Duktape.gc()").unwrap().pop(1);
+
+        assert_eq!(handler.callback.get_type(&ctx).unwrap(), Type::Function);
+        let func: Function = handler.callback.get(&ctx).unwrap();
+        let out: f64 = func.call((21, 2)).unwrap();
+        assert_eq!(out, 42.0);
+
+        let finalized = || -> bool { ctx.eval("Duktape.gc(); finalized").unwrap().getp().unwrap() };
+        let copy = handler.callback.clone();
+        assert_ne!(copy.refer, handler.callback.refer);
+        drop(func);
+        drop(copy);
+        // Still pinned by the original
+        assert!(!finalized());
+        drop(handler);
+        // Neither reference keeps the function alive anymore
+        assert!(finalized());
+        assert_eq!(ctx.top(), 0);
+    }
+
+    #[test]
+    fn heap_identity() {
+        let value = {
+            let ctx = DukContext::new().unwrap();
+            let other = DukContext::new().unwrap();
+            let value = PersistentRef::new(ctx.push_object(), -1).unwrap();
+            ctx.pop(1);
+
+            assert!(value.is_same_heap(&ctx));
+            assert!(!value.is_same_heap(&other));
+            assert!(value.get::<Object>(&other).is_err());
+            assert_eq!(other.top(), 0);
+
+            assert!(PersistentRef::new(&ctx, 0).is_err());
+            assert!(ctx.get::<PersistentRef>(-1).is_err());
+            value
+        };
+        // Released along with the heap, dropping it must not touch the heap
+        let copy = value.clone();
+        drop(value);
+        drop(copy);
+    }
+}
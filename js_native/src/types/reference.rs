@@ -20,6 +20,11 @@ impl<'a> Ref<'a> {
         Ref { ctx, refer }
     }
 
+    /// Take ownership of an entry of the refs table
+    pub(crate) fn from_raw(ctx: &'a DukContext, refer: u32) -> Ref<'a> {
+        Ref { ctx, refer }
+    }
+
     pub fn get_type(&self) -> Type {
         unsafe { push_ref(self.ctx.inner, self.refer) };
         let ret = self.ctx.get_type(-1);